@@ -1,28 +1,49 @@
-use crate::backend::event::{Entry, Event};
+use crate::backend::event::{Entry, Event, SplitBy};
 use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
 use bus::BusReader;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::collections::HashMap;
 
-pub struct AfdoReceiver {
-  writer: BufWriter<File>,
-  receiver: BusReceiver,
+#[derive(Default)]
+struct AfdoProfile {
   range_map: HashMap<(u64, u64), usize>,
   branch_map: HashMap<(u64, u64), usize>,
   last_record: (u64, u64),
+}
+
+pub struct AfdoReceiver {
+  receiver: BusReceiver,
+  // one profile per context key, a single one under None if not split
+  profiles: HashMap<Option<String>, AfdoProfile>,
+  split_by: SplitBy,
   elf_start: u64,
 }
 
 impl AfdoReceiver {
-  pub fn new(bus_rx: BusReader<Entry>, elf_start: u64) -> Self {
-    Self { writer: BufWriter::new(File::create("trace_afdo.txt").unwrap()), 
-            receiver: BusReceiver { name: "afdo".to_string(), bus_rx: bus_rx, checksum: 0 },
-            range_map: HashMap::new(),
-            branch_map: HashMap::new(),
-            last_record: (0, 0),
+  pub fn new(bus_rx: BusReader<Entry>, elf_start: u64, split_by: SplitBy) -> Self {
+    Self { receiver: BusReceiver { name: "afdo".to_string(), bus_rx: bus_rx, checksum: 0 },
+            profiles: HashMap::new(),
+            split_by,
             elf_start: elf_start }
   }
+
+  fn write_profile(&self, path: String, profile: &AfdoProfile) {
+    let mut writer = BufWriter::new(File::create(path).unwrap());
+    // write the range map 
+    writer.write_all(format!("{}\n", profile.range_map.len()).as_bytes()).unwrap();
+    for (key, value) in profile.range_map.iter() {
+      writer.write_all(format!("{:x}-{:x}:{}\n", key.0 - self.elf_start, key.1 - self.elf_start, value).as_bytes()).unwrap();
+    }
+    // write the sample record, which should always be 0
+    writer.write_all(b"0\n").unwrap();
+    // write the branch map 
+    writer.write_all(format!("{}\n", profile.branch_map.len()).as_bytes()).unwrap();
+    for (key, value) in profile.branch_map.iter() {
+      writer.write_all(format!("{:x}->{:x}:{}\n", key.0 - self.elf_start, key.1 - self.elf_start, value).as_bytes()).unwrap();
+    }
+    writer.flush().unwrap();
+  }
 }
 
 impl AbstractReceiver for AfdoReceiver {
@@ -36,32 +57,42 @@ impl AbstractReceiver for AfdoReceiver {
   }
 
   fn _receive_entry(&mut self, entry: Entry) {
+    let key = entry.context.key(self.split_by);
     match entry.event {
       Event::Start => {
-        self.last_record = (0, entry.arc.0);
+        self.profiles.entry(key).or_default().last_record = (0, entry.arc.0);
       }
       Event::TakenBranch | Event::InferrableJump | Event::UninferableJump => {
-          self.range_map.entry((self.last_record.1, entry.arc.0)).and_modify(|v| *v += 1).or_insert(1);
-          self.branch_map.entry((entry.arc.0, entry.arc.1)).and_modify(|v| *v += 1).or_insert(1);
-          self.last_record = (entry.arc.0, entry.arc.1);
+          let profile = self.profiles.entry(key).or_default();
+          profile.range_map.entry((profile.last_record.1, entry.arc.0)).and_modify(|v| *v += 1).or_insert(1);
+          profile.branch_map.entry((entry.arc.0, entry.arc.1)).and_modify(|v| *v += 1).or_insert(1);
+          profile.last_record = (entry.arc.0, entry.arc.1);
+      }
+      // a split profile picks up at the handler, and the interrupted one
+      // carries on from its last branch once the trap returns
+      Event::TrapException | Event::TrapInterrupt | Event::TrapReturn if key.is_some() => {
+          let profile = self.profiles.entry(key).or_insert_with(|| AfdoProfile { last_record: (0, entry.arc.1), ..Default::default() });
+          if entry.event != Event::TrapReturn {
+            profile.last_record = (0, entry.arc.1);
+          }
+      }
+      // a reported switch picks up where it happens
+      Event::AsidChange | Event::ModeChange if key.is_some() => {
+          self.profiles.entry(key).or_default().last_record = (0, entry.arc.0);
       }
       _ => {}
     }
   }
 
   fn _flush(&mut self) {
-    // write the range map 
-    self.writer.write_all(format!("{}\n", self.range_map.len()).as_bytes()).unwrap();
-    for (key, value) in self.range_map.iter() {
-      self.writer.write_all(format!("{:x}-{:x}:{}\n", key.0 - self.elf_start, key.1 - self.elf_start, value).as_bytes()).unwrap();
-    }
-    // write the sample record, which should always be 0
-    self.writer.write_all(b"0\n").unwrap();
-    // write the branch map 
-    self.writer.write_all(format!("{}\n", self.branch_map.len()).as_bytes()).unwrap();
-    for (key, value) in self.branch_map.iter() {
-      self.writer.write_all(format!("{:x}->{:x}:{}\n", key.0 - self.elf_start, key.1 - self.elf_start, value).as_bytes()).unwrap();
+    for (key, profile) in self.profiles.iter() {
+      let path = match key {
+        // contexts only passed through, e.g. before a reported mode, are left out
+        Some(_) if profile.branch_map.is_empty() => continue,
+        Some(key) => format!("trace_afdo.{}.txt", key),
+        None => String::from("trace_afdo.txt"),
+      };
+      self.write_profile(path, profile);
     }
-    self.writer.flush().unwrap();
   }
 }
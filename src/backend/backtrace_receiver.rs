@@ -0,0 +1,249 @@
+use crate::backend::event::{Context, Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame};
+use gcno_reader::cfg::SourceLocation;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use log::debug;
+
+#[derive(Serialize)]
+struct FrameJson {
+    function: String,
+    file: String,
+    line: u32,
+    entered: Option<u64>,
+    inferred: bool,
+}
+
+#[derive(Serialize, Clone)]
+struct TrapJson {
+    kind: Event,
+    timestamp: u64,
+    pc: u64,
+    symbol: String,
+    handler: u64,
+    cause: Option<u64>,
+    cause_description: Option<String>,
+    tval: Option<u64>,
+}
+
+// a task that was not running at the end of the trace
+#[derive(Serialize)]
+struct TaskJson {
+    task: u64,
+    frames: Vec<FrameJson>,
+}
+
+#[derive(Serialize)]
+struct BacktraceJson {
+    end_timestamp: u64,
+    pc: u64,
+    symbol: String,
+    context: Context,
+    // none unless stacks are kept per task
+    task: Option<u64>,
+    frames: Vec<FrameJson>,
+    other_tasks: Vec<TaskJson>,
+    last_trap: Option<TrapJson>,
+}
+
+fn frames_json(backtrace: Vec<(StackFrame, SourceLocation)>) -> Vec<FrameJson> {
+    backtrace.into_iter().map(|(frame, loc)| FrameJson {
+        function: frame.symbol.name,
+        file: loc.file,
+        line: loc.lines,
+        entered: frame.entered,
+        inferred: frame.inferred,
+    }).collect()
+}
+
+// standard xcause codes from the RISC-V privileged spec
+fn describe_cause(cause: u64) -> String {
+    let interrupt = cause >> 63 == 1;
+    let code = cause & !(1 << 63);
+    let description = match (interrupt, code) {
+        (true, 1) => "supervisor software interrupt",
+        (true, 3) => "machine software interrupt",
+        (true, 5) => "supervisor timer interrupt",
+        (true, 7) => "machine timer interrupt",
+        (true, 9) => "supervisor external interrupt",
+        (true, 11) => "machine external interrupt",
+        (true, _) => "platform interrupt",
+        (false, 0) => "instruction address misaligned",
+        (false, 1) => "instruction access fault",
+        (false, 2) => "illegal instruction",
+        (false, 3) => "breakpoint",
+        (false, 4) => "load address misaligned",
+        (false, 5) => "load access fault",
+        (false, 6) => "store/AMO address misaligned",
+        (false, 7) => "store/AMO access fault",
+        (false, 8) => "environment call from U-mode",
+        (false, 9) => "environment call from S-mode",
+        (false, 11) => "environment call from M-mode",
+        (false, 12) => "instruction page fault",
+        (false, 13) => "load page fault",
+        (false, 15) => "store/AMO page fault",
+        (false, _) => "unknown exception",
+    };
+    description.to_string()
+}
+
+pub struct BacktraceReceiver {
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // the last known pc: the last instruction executed, or where the last jump went
+    last_pc: u64,
+    last_timestamp: u64,
+    last_context: Context,
+    last_trap: Option<TrapJson>,
+}
+
+impl BacktraceReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, switch_symbols: Vec<String>) -> Self {
+        debug!("BacktraceReceiver::new");
+        let mut stack_unwinder = StackUnwinder::new(elf_path, debug_path).unwrap();
+        stack_unwinder.set_switch_symbols(&switch_symbols).unwrap();
+        Self {
+            receiver: BusReceiver {
+                name: "backtrace".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            last_pc: 0,
+            last_timestamp: 0,
+            last_context: Context::default(),
+            last_trap: None,
+        }
+    }
+
+    fn write_frames(writer: &mut BufWriter<File>, frames: &[FrameJson]) {
+        for (depth, frame) in frames.iter().enumerate() {
+            let entered = match frame.entered {
+                Some(entered) => format!(", entered at {}", entered),
+                None if frame.inferred => String::from(" [inferred]"),
+                None => String::new(),
+            };
+            writeln!(writer, "  #{} {} at {}:{}{}", depth, frame.function, frame.file, frame.line, entered).unwrap();
+        }
+    }
+
+    fn write_txt(&self, backtrace: &BacktraceJson) {
+        let mut writer = BufWriter::new(File::create("trace.backtrace.txt").unwrap());
+        writeln!(writer, "trace ended at timestamp {}, pc {:#x} ({}), in {}", backtrace.end_timestamp, backtrace.pc, backtrace.symbol, backtrace.context).unwrap();
+        match backtrace.task {
+            Some(task) => writeln!(writer, "backtrace of task {}:", task).unwrap(),
+            None => writeln!(writer, "backtrace:").unwrap(),
+        }
+        Self::write_frames(&mut writer, &backtrace.frames);
+        for task in backtrace.other_tasks.iter() {
+            writeln!(writer, "backtrace of task {} (switched out):", task.task).unwrap();
+            Self::write_frames(&mut writer, &task.frames);
+        }
+        match backtrace.last_trap.as_ref() {
+            Some(trap) => {
+                writeln!(writer, "last trap: {} at {:#x} ({}), timestamp {}, handler {:#x}", trap.kind.to_string(), trap.pc, trap.symbol, trap.timestamp, trap.handler).unwrap();
+                if let (Some(cause), Some(description)) = (trap.cause, trap.cause_description.as_ref()) {
+                    writeln!(writer, "  cause: {:#x} ({})", cause, description).unwrap();
+                }
+                if let Some(tval) = trap.tval {
+                    writeln!(writer, "  tval: {:#x}", tval).unwrap();
+                }
+            }
+            None => writeln!(writer, "last trap: none").unwrap(),
+        }
+        writer.flush().unwrap();
+    }
+
+    fn write_json(&self, backtrace: &BacktraceJson) {
+        let mut writer = BufWriter::new(File::create("trace.backtrace.json").unwrap());
+        writeln!(writer, "{}", serde_json::to_string_pretty(backtrace).unwrap()).unwrap();
+        writer.flush().unwrap();
+    }
+}
+
+impl AbstractReceiver for BacktraceReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = timestamp;
+        }
+        self.last_context = entry.context;
+        match entry.event {
+            Event::None => {
+                self.last_pc = entry.arc.0;
+            }
+            Event::Start => {
+                self.last_pc = entry.arc.0;
+            }
+            Event::TakenBranch => {
+                self.last_pc = entry.arc.1;
+            }
+            Event::InferrableJump | Event::UninferableJump | Event::TrapReturn => {
+                self.last_pc = entry.arc.1;
+            }
+            Event::TrapException | Event::TrapInterrupt => {
+                self.last_trap = Some(TrapJson {
+                    kind: entry.event,
+                    timestamp: entry.timestamp.unwrap(),
+                    pc: entry.arc.0,
+                    symbol: self.stack_unwinder.symbolize(entry.arc.0),
+                    handler: entry.arc.1,
+                    cause: None,
+                    cause_description: None,
+                    tval: None,
+                });
+                self.last_pc = entry.arc.1;
+            }
+            // reported right after the trap they belong to
+            Event::TrapCause => {
+                if let Some(trap) = self.last_trap.as_mut() {
+                    trap.cause = Some(entry.arc.1);
+                    trap.cause_description = Some(describe_cause(entry.arc.1));
+                }
+            }
+            Event::TrapValue => {
+                if let Some(trap) = self.last_trap.as_mut() {
+                    trap.tval = Some(entry.arc.1);
+                }
+            }
+            _ => {}
+        }
+        // the stack the trace ends with is what gets reported
+        if entry.event != Event::End && StackUnwinder::steps_on(entry.event) {
+            self.stack_unwinder.step(&entry);
+        }
+    }
+
+    fn _flush(&mut self) {
+        let frames = frames_json(self.stack_unwinder.backtrace(self.last_pc));
+        let other_tasks = self.stack_unwinder.parked_backtraces().into_iter()
+            .map(|(task, backtrace)| TaskJson { task, frames: frames_json(backtrace) })
+            .collect();
+        let backtrace = BacktraceJson {
+            end_timestamp: self.last_timestamp,
+            pc: self.last_pc,
+            symbol: self.stack_unwinder.symbolize(self.last_pc),
+            context: self.last_context,
+            task: self.stack_unwinder.tracks_tasks().then(|| self.stack_unwinder.task()),
+            frames,
+            other_tasks,
+            last_trap: self.last_trap.clone(),
+        };
+        self.write_txt(&backtrace);
+        self.write_json(&backtrace);
+    }
+}
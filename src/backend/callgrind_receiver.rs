@@ -0,0 +1,239 @@
+use crate::backend::event::{Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame, StackUpdate, INTERRUPT_FRAME_ADDR};
+use crate::backend::cost_accounting::{TickSpreader, CallSites};
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::{BTreeMap, HashMap};
+
+use log::debug;
+
+// instructions executed and timestamp ticks
+#[derive(Default, Clone, Copy)]
+struct Cost {
+    insns: u64,
+    cycles: u64,
+}
+
+// calls from one call site to one function
+#[derive(Default)]
+struct CallCost {
+    count: u64,
+    inclusive: Cost,
+}
+
+#[derive(Default)]
+struct FuncCost {
+    // instruction address -> self cost
+    insns: BTreeMap<u64, Cost>,
+    // (call site, callee function) -> calls
+    calls: BTreeMap<(u64, u64), CallCost>,
+}
+
+// an open frame, with the totals when it was entered to derive its inclusive cost
+struct CallRecord {
+    func: u64,
+    // the function it was called from and where, none for inferred frames
+    caller: Option<(u64, u64)>,
+    entered: Cost,
+}
+
+// costs per instruction and line, with inclusive costs of calls, as read by
+// KCachegrind and callgrind_annotate
+pub struct CallgrindReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    elf_path: String,
+    // function address -> costs
+    funcs: BTreeMap<u64, FuncCost>,
+    records: Vec<CallRecord>,
+    call_sites: CallSites,
+    // everything executed so far
+    total: Cost,
+    // instructions executed since the last timestamp, which share its ticks
+    spreader: TickSpreader<u64>,
+    last_addr: Option<u64>,
+    // addr -> (file, line)
+    location_cache: HashMap<u64, (String, u32)>,
+}
+
+impl CallgrindReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String) -> Self {
+        debug!("CallgrindReceiver::new");
+        Self {
+            writer: BufWriter::new(File::create("callgrind.out.trace").unwrap()),
+            receiver: BusReceiver {
+                name: "callgrind".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path.clone(), debug_path).unwrap(),
+            elf_path,
+            funcs: BTreeMap::new(),
+            records: Vec::new(),
+            call_sites: CallSites::new(),
+            total: Cost::default(),
+            spreader: TickSpreader::new(),
+            last_addr: None,
+            location_cache: HashMap::new(),
+        }
+    }
+
+    fn location(&mut self, addr: u64) -> (String, u32) {
+        let stack_unwinder = &self.stack_unwinder;
+        self.location_cache.entry(addr).or_insert_with(|| {
+            let loc = stack_unwinder.source_location(addr);
+            let file = if loc.file.is_empty() { String::from("???") } else { loc.file };
+            (file, loc.lines)
+        }).clone()
+    }
+
+    fn add_self_cost(&mut self, addr: u64, cost: Cost) {
+        let Some(func) = self.stack_unwinder.func_containing(addr) else { return };
+        let insn_cost = self.funcs.entry(func).or_default().insns.entry(addr).or_default();
+        insn_cost.insns += cost.insns;
+        insn_cost.cycles += cost.cycles;
+    }
+
+    // charge the ticks since the last timestamp to the instructions executed
+    // meanwhile, or to the last one if there were none
+    fn account(&mut self, timestamp: u64) {
+        let Some((ticks, shares)) = self.spreader.account(timestamp) else { return };
+        self.total.cycles += ticks;
+        if shares.is_empty() {
+            if let Some(addr) = self.last_addr {
+                self.add_self_cost(addr, Cost { insns: 0, cycles: ticks });
+            }
+            return;
+        }
+        for (addr, share) in shares {
+            self.add_self_cost(addr, Cost { insns: 0, cycles: share });
+        }
+    }
+
+    // keep the call records in step with the unwinder, charging closed calls to their edge
+    fn apply(&mut self, update: &StackUpdate, site: u64) {
+        self.call_sites.apply(update, site, &self.stack_unwinder);
+        for _ in update.closed.iter() {
+            let record = self.records.pop().unwrap();
+            if let Some((caller, call_site)) = record.caller {
+                let call = self.funcs.entry(caller).or_default().calls.entry((call_site, record.func)).or_default();
+                call.count += 1;
+                call.inclusive.insns += self.total.insns - record.entered.insns;
+                call.inclusive.cycles += self.total.cycles - record.entered.cycles;
+            }
+        }
+        for frame in update.opened.iter() {
+            let caller = match self.records.last() {
+                Some(caller) if !frame.inferred => Some((caller.func, self.call_sites.sites()[self.records.len()])),
+                _ => None,
+            };
+            self.records.push(CallRecord { func: frame.symbol.address, caller, entered: self.total });
+        }
+    }
+
+    fn func_name(&self, func: u64) -> String {
+        self.stack_unwinder.get_symbol_info(func).name
+    }
+
+    // where a function starts, trap frames have no code of their own
+    fn func_position(&mut self, func: u64) -> (String, u64, u32) {
+        if func >= INTERRUPT_FRAME_ADDR {
+            return (String::from("???"), 0, 0);
+        }
+        let (file, line) = self.location(func);
+        (file, func, line)
+    }
+
+    fn write_func(&mut self, func: u64, func_cost: &FuncCost) {
+        let (func_file, _, _) = self.func_position(func);
+        writeln!(self.writer, "fl={}", func_file).unwrap();
+        writeln!(self.writer, "fn={}", self.func_name(func)).unwrap();
+        let mut curr_file = func_file.clone();
+        for (addr, cost) in func_cost.insns.iter() {
+            let (file, line) = self.location(*addr);
+            // inlined code from another file
+            if file != curr_file {
+                writeln!(self.writer, "fi={}", file).unwrap();
+                curr_file = file;
+            }
+            writeln!(self.writer, "{:#x} {} {} {}", addr, line, cost.insns, cost.cycles).unwrap();
+        }
+        if curr_file != func_file {
+            writeln!(self.writer, "fe={}", func_file).unwrap();
+        }
+        for ((site, callee), call) in func_cost.calls.iter() {
+            let (callee_file, callee_addr, callee_line) = self.func_position(*callee);
+            let (_, site_line) = self.location(*site);
+            writeln!(self.writer, "cfi={}", callee_file).unwrap();
+            writeln!(self.writer, "cfn={}", self.func_name(*callee)).unwrap();
+            writeln!(self.writer, "calls={} {:#x} {}", call.count, callee_addr, callee_line).unwrap();
+            writeln!(self.writer, "{:#x} {} {} {}", site, site_line, call.inclusive.insns, call.inclusive.cycles).unwrap();
+        }
+        writeln!(self.writer).unwrap();
+    }
+}
+
+impl AbstractReceiver for CallgrindReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        match entry.event {
+            Event::None => {
+                let addr = entry.arc.0;
+                self.add_self_cost(addr, Cost { insns: 1, cycles: 0 });
+                self.total.insns += 1;
+                self.spreader.push(addr);
+                self.last_addr = Some(addr);
+            }
+            Event::Start => {
+                self.spreader.start(entry.timestamp);
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.arc.0);
+            }
+            event if StackUnwinder::steps_on(event) => {
+                self.account(entry.timestamp.unwrap());
+                // nothing is accounted between an end and the next start
+                if entry.event == Event::End {
+                    self.spreader.stop();
+                }
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.arc.0);
+            }
+            _ => {
+                if let Some(timestamp) = entry.timestamp {
+                    self.account(timestamp);
+                }
+            }
+        }
+    }
+
+    fn _flush(&mut self) {
+        // calls still open are charged up to the end of the trace
+        let closed: Vec<StackFrame> = self.stack_unwinder.flush();
+        self.apply(&StackUpdate { closed, opened: Vec::new(), depth: 0 }, 0);
+
+        writeln!(self.writer, "version: 1").unwrap();
+        writeln!(self.writer, "creator: ltrace-decoder").unwrap();
+        writeln!(self.writer, "positions: instr line").unwrap();
+        writeln!(self.writer, "events: Instructions Cycles").unwrap();
+        writeln!(self.writer, "summary: {} {}", self.total.insns, self.total.cycles).unwrap();
+        writeln!(self.writer).unwrap();
+        writeln!(self.writer, "ob={}", self.elf_path).unwrap();
+        let funcs = std::mem::take(&mut self.funcs);
+        for (func, func_cost) in funcs.iter() {
+            self.write_func(*func, func_cost);
+        }
+        self.writer.flush().unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+use crate::backend::event::{Entry, Event, ControlFlowKind};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::StackUnwinder;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+
+use log::debug;
+
+// one level of the return-address shadow stack
+struct ShadowEntry {
+    // where the matching return must land
+    return_addr: u64,
+    // set for traps: where a trap return may land, the trapping pc and, for
+    // exceptions, the instruction after it (e.g. ecall handlers skip the ecall)
+    trap_return_addrs: Option<(u64, u64)>,
+}
+
+pub struct CfiReceiver {
+    writer: Box<dyn Write + Send>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // the shadow stack of the running task
+    shadow_stack: Vec<ShadowEntry>,
+    // task -> shadow stack, for the tasks not running
+    parked_stacks: HashMap<u64, Vec<ShadowEntry>>,
+    violations: usize,
+}
+
+impl CfiReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, switch_symbols: Vec<String>) -> Self {
+        debug!("CfiReceiver::new");
+        let mut stack_unwinder = StackUnwinder::new(elf_path, debug_path).unwrap();
+        stack_unwinder.set_switch_symbols(&switch_symbols).unwrap();
+        Self::with_unwinder(bus_rx, Box::new(BufWriter::new(File::create("trace.cfi.txt").unwrap())), stack_unwinder)
+    }
+
+    fn with_unwinder(bus_rx: BusReader<Entry>, writer: Box<dyn Write + Send>, stack_unwinder: StackUnwinder) -> Self {
+        Self {
+            writer,
+            receiver: BusReceiver {
+                name: "cfi".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            shadow_stack: Vec::new(),
+            parked_stacks: HashMap::new(),
+            violations: 0,
+        }
+    }
+
+    // write a violation together with the call stack it happened in
+    // flushed right away, a hijacked trace may well crash the decoder next
+    fn report(&mut self, entry: &Entry, what: String) {
+        self.violations += 1;
+        let loc = self.stack_unwinder.source_location(entry.arc.0);
+        writeln!(self.writer, "[timestamp: {}] {}: {:#x} -> {:#x}", entry.timestamp.unwrap_or(0), what, entry.arc.0, entry.arc.1).unwrap();
+        writeln!(self.writer, "    at {}:{}", loc.file, loc.lines).unwrap();
+        writeln!(self.writer, "    call stack:").unwrap();
+        for (depth, frame) in self.stack_unwinder.stack().iter().rev().enumerate() {
+            let inferred = if frame.inferred { " [inferred]" } else { "" };
+            writeln!(self.writer, "      #{} {}{} ({}:{})", depth, frame.symbol.name, inferred, frame.symbol.file, frame.symbol.line).unwrap();
+        }
+        self.writer.flush().unwrap();
+    }
+
+    // entries below this depth belong to code interrupted by a trap
+    fn trap_depth(&self) -> usize {
+        self.shadow_stack.iter().rposition(|e| e.trap_return_addrs.is_some()).map_or(0, |i| i + 1)
+    }
+
+    fn check_target(&mut self, entry: &Entry) {
+        if self.stack_unwinder.insn_at(entry.arc.1).is_none() {
+            self.report(entry, "target is not an instruction boundary".to_string());
+        }
+    }
+
+    fn check_jump(&mut self, entry: &Entry) {
+        let target = entry.arc.1;
+        // the address a call made by this jump returns to
+        let return_addr = entry.arc.0 + self.stack_unwinder.insn_at(entry.arc.0).map_or(0, |insn| insn.len as u64);
+        match entry.cf_kind {
+            ControlFlowKind::Call => {
+                self.shadow_stack.push(ShadowEntry { return_addr, trap_return_addrs: None });
+            }
+            ControlFlowKind::IndirectCall => {
+                if !self.stack_unwinder.func_symbol_map().contains_key(&target) {
+                    self.report(entry, "indirect call to a non-entry address".to_string());
+                }
+                self.shadow_stack.push(ShadowEntry { return_addr, trap_return_addrs: None });
+            }
+            ControlFlowKind::Return => {
+                match self.shadow_stack.last() {
+                    // the call happened before the trace started, nothing to check against
+                    None => {}
+                    Some(top) if top.trap_return_addrs.is_some() => {
+                        self.report(entry, "return out of a trap handler".to_string());
+                    }
+                    Some(top) if top.return_addr != target => {
+                        let what = format!("return mismatch, expected {:#x}", top.return_addr);
+                        self.report(entry, what);
+                        // resync with the frame the return went to, if any, else drop the top
+                        let trap_depth = self.trap_depth();
+                        let depth = self.shadow_stack[trap_depth..].iter().rposition(|e| e.return_addr == target).map_or(self.shadow_stack.len() - 1, |i| trap_depth + i);
+                        self.shadow_stack.truncate(depth);
+                    }
+                    Some(_) => {
+                        self.shadow_stack.pop();
+                    }
+                }
+            }
+            // a non-local return (e.g. longjmp) drops the frames it skips
+            ControlFlowKind::IndirectJump => {
+                let trap_depth = self.trap_depth();
+                if let Some(depth) = self.shadow_stack[trap_depth..].iter().rposition(|e| e.return_addr == target) {
+                    self.shadow_stack.truncate(trap_depth + depth);
+                }
+            }
+            // returns through one link register and calls through the other,
+            // the two sides of a coroutine do not pair up, so it is not checked
+            ControlFlowKind::CoroutineSwap => {
+                if self.shadow_stack.last().is_some_and(|top| top.trap_return_addrs.is_none()) {
+                    self.shadow_stack.pop();
+                }
+                self.shadow_stack.push(ShadowEntry { return_addr, trap_return_addrs: None });
+            }
+            _ => {}
+        }
+    }
+
+    fn check_trap(&mut self, entry: &Entry) {
+        match entry.event {
+            Event::TrapException | Event::TrapInterrupt => {
+                let trap_pc = entry.arc.0;
+                let resume_pc = if entry.event == Event::TrapException {
+                    trap_pc + self.stack_unwinder.insn_at(trap_pc).map_or(0, |insn| insn.len as u64)
+                } else {
+                    trap_pc
+                };
+                self.shadow_stack.push(ShadowEntry { return_addr: trap_pc, trap_return_addrs: Some((trap_pc, resume_pc)) });
+            }
+            Event::TrapReturn => {
+                // the trap was taken before the trace started, nothing to check against
+                let Some(depth) = self.shadow_stack.iter().rposition(|e| e.trap_return_addrs.is_some()) else {
+                    return;
+                };
+                let (trap_pc, resume_pc) = self.shadow_stack[depth].trap_return_addrs.unwrap();
+                if entry.arc.1 != trap_pc && entry.arc.1 != resume_pc {
+                    let what = format!("trap return mismatch, expected {:#x}", trap_pc);
+                    self.report(entry, what);
+                }
+                self.shadow_stack.truncate(depth);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl AbstractReceiver for CfiReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        // a return or trap return into another task is checked against the stack of that task
+        let switches_task = self.stack_unwinder.switches_task(&entry);
+        if switches_task {
+            let prev_task = self.stack_unwinder.task();
+            self.stack_unwinder.step(&entry);
+            let shadow_stack = self.parked_stacks.remove(&self.stack_unwinder.task()).unwrap_or_default();
+            self.parked_stacks.insert(prev_task, std::mem::replace(&mut self.shadow_stack, shadow_stack));
+        }
+        match entry.event {
+            Event::InferrableJump | Event::UninferableJump => {
+                // checked against the stack the jump was taken from
+                self.check_target(&entry);
+                self.check_jump(&entry);
+            }
+            Event::TrapException | Event::TrapInterrupt | Event::TrapReturn => {
+                self.check_target(&entry);
+                self.check_trap(&entry);
+            }
+            Event::TakenBranch => {
+                self.check_target(&entry);
+            }
+            Event::Start => {
+                self.shadow_stack.clear();
+            }
+            _ => {}
+        }
+        if StackUnwinder::steps_on(entry.event) && !switches_task {
+            self.stack_unwinder.step(&entry);
+        }
+    }
+
+    fn _flush(&mut self) {
+        println!("[cfi] {} control-flow violations found", self.violations);
+        self.writer.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stack_unwinder::{InsnInfo, SymbolInfo};
+
+    use indexmap::IndexMap;
+
+    const MAIN: u64 = 0x1000;
+    const F: u64 = 0x1100;
+    const G: u64 = 0x1200;
+
+    // functions of 0x100 bytes of 4-byte instructions
+    fn receiver(switch_symbols: &[&str]) -> CfiReceiver {
+        let mut func_symbol_map = IndexMap::new();
+        for (index, (address, name)) in [(MAIN, "main"), (F, "f"), (G, "g")].into_iter().enumerate() {
+            func_symbol_map.insert(address, SymbolInfo { address, name: name.to_string(), mangled_name: name.to_string(), index: index as u32, line: 0, file: String::new() });
+        }
+        let insn_map = (MAIN..G + 0x100).step_by(4).map(|address| (address, InsnInfo { address, len: 4, bytes: vec![0; 4] })).collect();
+        let mut stack_unwinder = StackUnwinder::from_maps(func_symbol_map, insn_map, None);
+        stack_unwinder.set_switch_symbols(&switch_symbols.iter().map(|symbol| symbol.to_string()).collect::<Vec<String>>()).unwrap();
+        let mut receiver = CfiReceiver::with_unwinder(bus::Bus::new(1).add_rx(), Box::new(std::io::sink()), stack_unwinder);
+        receiver._receive_entry(Entry::new_timed_event(Event::Start, 0, MAIN, 0));
+        receiver
+    }
+
+    fn jump(receiver: &mut CfiReceiver, cf_kind: ControlFlowKind, from: u64, to: u64) -> usize {
+        receiver._receive_entry(Entry::new_timed_jump(Event::UninferableJump, cf_kind, 0, from, to));
+        receiver.violations
+    }
+
+    #[test]
+    fn test_matched_return() {
+        let mut receiver = receiver(&[]);
+        jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x10, F);
+        jump(&mut receiver, ControlFlowKind::IndirectCall, F + 0x20, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, F + 0x24), 0);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, F + 0x8, MAIN + 0x14), 0);
+        assert!(receiver.shadow_stack.is_empty());
+        // the call happened before the trace started
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, MAIN + 0x20, G + 0x40), 0);
+    }
+
+    #[test]
+    fn test_mismatched_return() {
+        let mut receiver = receiver(&[]);
+        jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x10, F);
+        jump(&mut receiver, ControlFlowKind::Call, F + 0x20, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, MAIN + 0x40), 1);
+        assert_eq!(receiver.shadow_stack.len(), 1);
+        // skipping frames on the way back is reported once, then back in step
+        jump(&mut receiver, ControlFlowKind::Call, F + 0x20, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, MAIN + 0x14), 2);
+        assert!(receiver.shadow_stack.is_empty());
+    }
+
+    #[test]
+    fn test_indirect_call_to_non_entry() {
+        let mut receiver = receiver(&[]);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::IndirectCall, MAIN + 0x10, F), 0);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::IndirectCall, F + 0x10, G + 0x20), 1);
+        // a direct call is not checked for it
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Call, F + 0x20, G + 0x20), 1);
+    }
+
+    #[test]
+    fn test_jump_to_non_instruction_boundary() {
+        let mut receiver = receiver(&[]);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Jump, MAIN + 0x10, MAIN + 0x20), 0);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Jump, MAIN + 0x20, MAIN + 0x22), 1);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x24, G + 0x100), 2);
+        receiver._receive_entry(Entry::new_timed_event(Event::TakenBranch, 0, MAIN + 0x30, MAIN + 0x31));
+        assert_eq!(receiver.violations, 3);
+    }
+
+    #[test]
+    fn test_shadow_stack_per_task() {
+        let mut receiver = receiver(&["g"]);
+        jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x10, G);
+        // g returns into a new task, which later switches back
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, F + 0x30), 0);
+        jump(&mut receiver, ControlFlowKind::Call, F + 0x40, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, MAIN + 0x14), 0);
+        assert!(receiver.shadow_stack.is_empty());
+        assert_eq!(receiver.parked_stacks[&1].len(), 1);
+        // and back to the second task
+        jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x20, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, F + 0x44), 0);
+        // a task named by the trace
+        receiver._receive_entry(Entry::new_timed_event(Event::TaskSwitch, 0, G + 0x40, 9));
+        jump(&mut receiver, ControlFlowKind::Call, G + 0x40, F);
+        receiver._receive_entry(Entry::new_timed_event(Event::TaskSwitch, 0, G + 0x8, 0));
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, MAIN + 0x24), 0);
+        receiver._receive_entry(Entry::new_timed_event(Event::TaskSwitch, 0, F + 0x8, 9));
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, F + 0x8, G + 0x44), 0);
+    }
+
+    // without switch symbols, both returns are mismatched
+    #[test]
+    fn test_one_stack_without_tasks() {
+        let mut receiver = receiver(&[]);
+        jump(&mut receiver, ControlFlowKind::Call, MAIN + 0x10, G);
+        jump(&mut receiver, ControlFlowKind::Return, G + 0x8, F + 0x30);
+        jump(&mut receiver, ControlFlowKind::Call, F + 0x40, G);
+        assert_eq!(jump(&mut receiver, ControlFlowKind::Return, G + 0x8, MAIN + 0x14), 2);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::backend::event::{Entry, Event, PrivMode};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame};
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::BTreeSet;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use log::debug;
+
+// the decoder follows a single hart, it is the only process in the trace
+const HART_PID: u64 = 0;
+
+// one event of the trace event format, as read by chrome://tracing and Perfetto
+#[derive(Serialize)]
+struct TraceEvent {
+    name: String,
+    cat: String,
+    ph: String,
+    // microseconds
+    ts: f64,
+    pid: u64,
+    tid: u64,
+    // scope of an instant event, t for its thread
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<Value>,
+}
+
+pub struct ChromeTraceReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // timestamp ticks per second
+    clock_hz: u64,
+    events: Vec<TraceEvent>,
+    // the track each open frame was opened on, outermost first
+    frame_tids: Vec<u64>,
+    // tracks used so far, named at the end
+    modes: BTreeSet<u64>,
+    // where the last trap instant is, to add its cause and value to
+    last_trap: Option<usize>,
+    start: u64,
+    last_timestamp: u64,
+}
+
+impl ChromeTraceReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, clock_hz: u64) -> Self {
+        debug!("ChromeTraceReceiver::new");
+        Self {
+            writer: BufWriter::new(File::create("trace.chrome.json").unwrap()),
+            receiver: BusReceiver {
+                name: "chrome_trace".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path, debug_path).unwrap(),
+            clock_hz,
+            events: Vec::new(),
+            frame_tids: Vec::new(),
+            modes: BTreeSet::new(),
+            last_trap: None,
+            start: 0,
+            last_timestamp: 0,
+        }
+    }
+
+    fn to_us(&self, timestamp: u64) -> f64 {
+        timestamp as f64 * 1e6 / self.clock_hz as f64
+    }
+
+    // one track per privilege mode, numbered by level
+    fn tid_of(&mut self, mode: PrivMode) -> u64 {
+        self.modes.insert(mode.level());
+        mode.level()
+    }
+
+    fn open_frame(&mut self, frame: &StackFrame, mode: PrivMode, at: u64) {
+        let tid = self.tid_of(mode);
+        self.frame_tids.push(tid);
+        let (name, at) = if frame.inferred {
+            (format!("{} [inferred]", frame.symbol.name), self.start)
+        } else {
+            (frame.symbol.name.clone(), at)
+        };
+        let event = TraceEvent {
+            name,
+            cat: "function".to_string(),
+            ph: "B".to_string(),
+            ts: self.to_us(at),
+            pid: HART_PID,
+            tid,
+            s: None,
+            args: Some(json!({"file": frame.symbol.file, "line": frame.symbol.line})),
+        };
+        if frame.inferred {
+            // open since the start, below everything recorded so far
+            self.events.insert(0, event);
+            self.last_trap = self.last_trap.map(|index| index + 1);
+        } else {
+            self.events.push(event);
+        }
+    }
+
+    fn close_frame(&mut self, frame: &StackFrame, at: u64) {
+        let tid = self.frame_tids.pop().unwrap();
+        let name = if frame.inferred { format!("{} [inferred]", frame.symbol.name) } else { frame.symbol.name.clone() };
+        self.events.push(TraceEvent { name, cat: "function".to_string(), ph: "E".to_string(), ts: self.to_us(at), pid: HART_PID, tid, s: None, args: None });
+    }
+
+    fn trap_instant(&mut self, entry: &Entry) {
+        let tid = self.tid_of(entry.context.mode);
+        let name = match entry.event {
+            Event::TrapException => "exception",
+            _ => "interrupt",
+        };
+        self.last_trap = Some(self.events.len());
+        self.events.push(TraceEvent {
+            name: name.to_string(),
+            cat: "trap".to_string(),
+            ph: "i".to_string(),
+            ts: self.to_us(entry.timestamp.unwrap()),
+            pid: HART_PID,
+            tid,
+            s: Some("t".to_string()),
+            args: Some(json!({"pc": format!("{:#x}", entry.arc.0), "handler": format!("{:#x}", entry.arc.1)})),
+        });
+    }
+
+    // process and track names, so the tracks read "hart 0" / "M-mode"
+    fn metadata(&self) -> Vec<Value> {
+        let mut metadata = vec![json!({"name": "process_name", "ph": "M", "pid": HART_PID, "args": {"name": format!("hart {}", HART_PID)}})];
+        for level in self.modes.iter() {
+            // only levels of modes seen are recorded
+            let mode = PrivMode::from_level(*level).unwrap();
+            metadata.push(json!({"name": "thread_name", "ph": "M", "pid": HART_PID, "tid": level, "args": {"name": format!("{}-mode", mode)}}));
+            metadata.push(json!({"name": "thread_sort_index", "ph": "M", "pid": HART_PID, "tid": level, "args": {"sort_index": level}}));
+        }
+        metadata
+    }
+}
+
+impl AbstractReceiver for ChromeTraceReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = timestamp;
+        }
+        match entry.event {
+            Event::Start => {
+                self.start = entry.timestamp.unwrap();
+                for frame in self.stack_unwinder.step(&entry).opened.iter() {
+                    self.open_frame(frame, entry.context.mode, self.start);
+                }
+            }
+            event if StackUnwinder::steps_on(event) => {
+                let timestamp = entry.timestamp.unwrap();
+                if matches!(entry.event, Event::TrapException | Event::TrapInterrupt) {
+                    self.trap_instant(&entry);
+                }
+                let update = self.stack_unwinder.step(&entry);
+                for frame in update.closed.iter() {
+                    self.close_frame(frame, timestamp);
+                }
+                // a trap opens its frames on the track of the mode it enters
+                for frame in update.opened.iter() {
+                    self.open_frame(frame, entry.context.mode, timestamp);
+                }
+            }
+            // reported right after the trap they belong to
+            Event::TrapCause | Event::TrapValue => {
+                if let Some(Some(args)) = self.last_trap.map(|index| self.events[index].args.as_mut()) {
+                    let key = if entry.event == Event::TrapCause { "cause" } else { "tval" };
+                    args[key] = json!(format!("{:#x}", entry.arc.1));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn _flush(&mut self) {
+        // close whatever is still open, if the trace ended without an End
+        for frame in self.stack_unwinder.flush().iter() {
+            self.close_frame(frame, self.last_timestamp);
+        }
+        let mut trace_events = self.metadata();
+        for event in self.events.iter() {
+            trace_events.push(serde_json::to_value(event).unwrap());
+        }
+        let trace = json!({"traceEvents": trace_events, "displayTimeUnit": "ns"});
+        writeln!(self.writer, "{}", serde_json::to_string(&trace).unwrap()).unwrap();
+        self.writer.flush().unwrap();
+    }
+}
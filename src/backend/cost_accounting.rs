@@ -0,0 +1,141 @@
+use crate::backend::stack_unwinder::{StackUnwinder, StackUpdate};
+
+// timestamps only come with some packets, the ticks between two of them are
+// shared out over the work done meanwhile
+pub struct TickSpreader<T> {
+    // items since the last timestamp and their weights, in order of execution
+    pending: Vec<(T, u64)>,
+    // none until the first start and between an end and the next start
+    last_timestamp: Option<u64>,
+}
+
+impl<T: PartialEq> TickSpreader<T> {
+    pub fn new() -> Self {
+        Self {
+            pending: Vec::new(),
+            last_timestamp: None,
+        }
+    }
+
+    // one unit of work, a repeat of the last item adds to its weight
+    pub fn push(&mut self, item: T) {
+        match self.pending.last_mut() {
+            Some((last, weight)) if *last == item => *weight += 1,
+            _ => self.pending.push((item, 1)),
+        }
+    }
+
+    pub fn start(&mut self, timestamp: Option<u64>) {
+        self.last_timestamp = timestamp;
+    }
+
+    // nothing is accounted until the next start
+    pub fn stop(&mut self) {
+        self.last_timestamp = None;
+    }
+
+    // work after the last timestamp took no measurable time
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    // the ticks since the last timestamp and their shares by weight, none before
+    // the first timestamp; the shares are empty if nothing was pushed meanwhile
+    pub fn account(&mut self, timestamp: u64) -> Option<(u64, Vec<(T, u64)>)> {
+        let pending = std::mem::take(&mut self.pending);
+        let last_timestamp = self.last_timestamp.replace(timestamp)?;
+        let ticks = timestamp.saturating_sub(last_timestamp);
+        let total: u64 = pending.iter().map(|(_, weight)| weight).sum();
+        // rounding on the running sum, so that the shares add up to ticks
+        let mut done = 0;
+        let shares = pending.into_iter().map(|(item, weight)| {
+            let share = ticks * (done + weight) / total - ticks * done / total;
+            done += weight;
+            (item, share)
+        }).collect();
+        Some((ticks, shares))
+    }
+}
+
+// where each open frame was called from, kept in step with the unwinder
+pub struct CallSites {
+    // outermost first
+    sites: Vec<u64>,
+}
+
+impl CallSites {
+    pub fn new() -> Self {
+        Self { sites: Vec::new() }
+    }
+
+    pub fn sites(&self) -> &[u64] {
+        &self.sites
+    }
+
+    // apply an update of the unwinder, site being the address it stepped at
+    pub fn apply(&mut self, update: &StackUpdate, site: u64, stack_unwinder: &StackUnwinder) {
+        let mut replaced_site = None;
+        for _ in update.closed.iter() {
+            replaced_site = self.sites.pop();
+        }
+        let stack = stack_unwinder.stack();
+        for frame in update.opened.iter() {
+            // a tail call is made from where the replaced function was called
+            let in_caller = match self.sites.len() {
+                0 => true,
+                depth => stack_unwinder.func_containing(site) == Some(stack[depth - 1].symbol.address),
+            };
+            let call_site = if frame.inferred || in_caller { site } else { replaced_site.unwrap_or(site) };
+            self.sites.push(call_site);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_even_spread() {
+        let mut spreader = TickSpreader::new();
+        spreader.start(Some(100));
+        for addr in [1, 2, 3] {
+            spreader.push(addr);
+        }
+        assert_eq!(spreader.account(110), Some((10, vec![(1, 3), (2, 3), (3, 4)])));
+    }
+
+    #[test]
+    fn test_weighted_spread() {
+        let mut spreader = TickSpreader::new();
+        spreader.start(Some(0));
+        for stack in ["a", "a", "b", "a"] {
+            spreader.push(stack);
+        }
+        // the repeat adds up, the later "a" is a new item
+        assert_eq!(spreader.account(7), Some((7, vec![("a", 3), ("b", 2), ("a", 2)])));
+    }
+
+    #[test]
+    fn test_nothing_pending() {
+        let mut spreader: TickSpreader<u64> = TickSpreader::new();
+        spreader.start(Some(5));
+        assert_eq!(spreader.account(8), Some((3, vec![])));
+        assert_eq!(spreader.account(8), Some((0, vec![])));
+    }
+
+    #[test]
+    fn test_stopped() {
+        let mut spreader = TickSpreader::new();
+        spreader.push(1);
+        // nothing before the first timestamp
+        assert_eq!(spreader.account(4), None);
+        spreader.push(2);
+        assert_eq!(spreader.account(6), Some((2, vec![(2, 2)])));
+        spreader.stop();
+        spreader.push(3);
+        assert_eq!(spreader.account(9), None);
+        spreader.start(Some(10));
+        assert_eq!(spreader.account(12), Some((2, vec![])));
+    }
+}
@@ -0,0 +1,421 @@
+use crate::backend::event::{Entry, Event, PrivMode};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame};
+
+use bus::BusReader;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use log::debug;
+
+const CTF_DIR: &str = "trace.ctf";
+
+// packet.header magic, as in the CTF specification
+const CTF_MAGIC: u32 = 0xC1FC1FC1;
+const STREAM_ID: u32 = 0;
+// the decoder follows a single hart
+const CPU_ID: u32 = 0;
+
+// magic, stream_id, then timestamp_begin, timestamp_end, content_size,
+// packet_size, events_discarded and cpu_id
+const PACKET_HEADER_SIZE: usize = 4 + 4 + 8 * 5 + 4;
+// events are buffered into packets of about this many bytes
+const PACKET_SIZE_LIMIT: usize = 1 << 20;
+
+// event class ids, see the metadata
+const FUNC_ENTRY_ID: u32 = 0;
+const FUNC_EXIT_ID: u32 = 1;
+const BRANCH_ID: u32 = 2;
+const TRAP_ID: u32 = 3;
+const VALUE_ID: u32 = 4;
+
+// all fields are byte aligned and little endian, so they are written back to back
+const METADATA: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+typealias integer { size = 64; align = 8; signed = false; base = 16; } := addr_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+        uint32_t stream_id;
+    };
+};
+
+env {
+    tracer_name = "ltrace-decoder";
+    domain = "riscv-ltrace";
+};
+
+clock {
+    name = trace_clock;
+    description = "trace timestamp counter";
+    freq = @CLOCK_HZ@;
+    offset = 0;
+    precision = 1;
+    absolute = FALSE;
+};
+
+typealias integer { size = 64; align = 8; signed = false; map = clock.trace_clock.value; } := trace_clock_t;
+
+stream {
+    id = 0;
+    packet.context := struct {
+        trace_clock_t timestamp_begin;
+        trace_clock_t timestamp_end;
+        uint64_t content_size;
+        uint64_t packet_size;
+        uint64_t events_discarded;
+        uint32_t cpu_id;
+    };
+    event.header := struct {
+        uint32_t id;
+        trace_clock_t timestamp;
+    };
+    event.context := struct {
+        enum : uint8_t { U = 0, S = 1, M = 3 } mode;
+    };
+};
+
+event {
+    name = "func_entry";
+    id = 0;
+    stream_id = 0;
+    fields := struct {
+        addr_t addr;
+        addr_t call_site;
+        uint8_t inferred;
+        string name;
+    };
+};
+
+event {
+    name = "func_exit";
+    id = 1;
+    stream_id = 0;
+    fields := struct {
+        addr_t addr;
+        string name;
+    };
+};
+
+event {
+    name = "branch";
+    id = 2;
+    stream_id = 0;
+    fields := struct {
+        enum : uint8_t { taken = 0, not_taken = 1, inferrable_jump = 2, uninferable_jump = 3 } kind;
+        addr_t from;
+        addr_t to;
+    };
+};
+
+event {
+    name = "trap";
+    id = 3;
+    stream_id = 0;
+    fields := struct {
+        enum : uint8_t { exception = 0, interrupt = 1, trap_return = 2 } kind;
+        addr_t pc;
+        addr_t target;
+    };
+};
+
+event {
+    name = "value";
+    id = 4;
+    stream_id = 0;
+    fields := struct {
+        enum : uint8_t { cause = 0, tval = 1, asid = 2, mode = 3, task = 4 } kind;
+        addr_t pc;
+        uint64_t value;
+    };
+};
+"#;
+
+fn metadata(clock_hz: u64) -> String {
+    METADATA.replace("@CLOCK_HZ@", &clock_hz.to_string())
+}
+
+// a CTF 1.8 trace, a TSDL metadata file and one binary stream, for Trace Compass
+// and babeltrace
+pub struct CtfReceiver {
+    writer: Box<dyn Write + Send>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // events of the packet being filled, and the time it covers
+    packet: Vec<u8>,
+    packet_begin: Option<u64>,
+    last_timestamp: u64,
+    // the mode of the entry being written, the context of its events
+    mode: PrivMode,
+}
+
+impl CtfReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, clock_hz: u64) -> Self {
+        debug!("CtfReceiver::new");
+        fs::create_dir_all(CTF_DIR).unwrap();
+        fs::write(Path::new(CTF_DIR).join("metadata"), metadata(clock_hz)).unwrap();
+        let writer = BufWriter::new(File::create(Path::new(CTF_DIR).join(format!("stream_{}", STREAM_ID))).unwrap());
+        Self::with_unwinder(bus_rx, Box::new(writer), StackUnwinder::new(elf_path, debug_path).unwrap())
+    }
+
+    fn with_unwinder(bus_rx: BusReader<Entry>, writer: Box<dyn Write + Send>, stack_unwinder: StackUnwinder) -> Self {
+        Self {
+            writer,
+            receiver: BusReceiver {
+                name: "ctf".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            packet: Vec::new(),
+            packet_begin: None,
+            last_timestamp: 0,
+            mode: PrivMode::default(),
+        }
+    }
+
+    // event.header and event.context, the fields follow
+    fn begin_event(&mut self, id: u32) {
+        self.packet_begin.get_or_insert(self.last_timestamp);
+        self.packet.extend_from_slice(&id.to_le_bytes());
+        self.packet.extend_from_slice(&self.last_timestamp.to_le_bytes());
+        self.packet.push(self.mode.level() as u8);
+    }
+
+    fn u64_field(&mut self, value: u64) {
+        self.packet.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string_field(&mut self, value: &str) {
+        self.packet.extend_from_slice(value.as_bytes());
+        self.packet.push(0);
+    }
+
+    fn func_entry(&mut self, frame: &StackFrame, call_site: u64) {
+        self.begin_event(FUNC_ENTRY_ID);
+        self.u64_field(frame.symbol.address);
+        self.u64_field(call_site);
+        self.packet.push(frame.inferred as u8);
+        self.string_field(&frame.symbol.name);
+    }
+
+    fn func_exit(&mut self, frame: &StackFrame) {
+        self.begin_event(FUNC_EXIT_ID);
+        self.u64_field(frame.symbol.address);
+        self.string_field(&frame.symbol.name);
+    }
+
+    // an event with a kind, an address and a value
+    fn kind_event(&mut self, id: u32, kind: u8, addr: u64, value: u64) {
+        self.begin_event(id);
+        self.packet.push(kind);
+        self.u64_field(addr);
+        self.u64_field(value);
+    }
+
+    fn flush_packet(&mut self) {
+        let Some(packet_begin) = self.packet_begin.take() else { return };
+        let size_bits = ((PACKET_HEADER_SIZE + self.packet.len()) * 8) as u64;
+        self.writer.write_all(&CTF_MAGIC.to_le_bytes()).unwrap();
+        self.writer.write_all(&STREAM_ID.to_le_bytes()).unwrap();
+        for value in [packet_begin, self.last_timestamp, size_bits, size_bits, 0] {
+            self.writer.write_all(&value.to_le_bytes()).unwrap();
+        }
+        self.writer.write_all(&CPU_ID.to_le_bytes()).unwrap();
+        self.writer.write_all(&self.packet).unwrap();
+        self.packet.clear();
+    }
+}
+
+impl AbstractReceiver for CtfReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        // entries without a timestamp happened no earlier than the last one
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = self.last_timestamp.max(timestamp);
+        }
+        self.mode = entry.context.mode;
+        match entry.event {
+            Event::None | Event::Start | Event::End => {}
+            Event::TakenBranch | Event::NonTakenBranch => {
+                let kind = if entry.event == Event::TakenBranch { 0 } else { 1 };
+                self.kind_event(BRANCH_ID, kind, entry.arc.0, entry.arc.1);
+            }
+            Event::InferrableJump | Event::UninferableJump | Event::TrapException | Event::TrapInterrupt | Event::TrapReturn => {
+                let (id, kind) = match entry.event {
+                    Event::InferrableJump => (BRANCH_ID, 2),
+                    Event::UninferableJump => (BRANCH_ID, 3),
+                    Event::TrapException => (TRAP_ID, 0),
+                    Event::TrapInterrupt => (TRAP_ID, 1),
+                    _ => (TRAP_ID, 2),
+                };
+                self.kind_event(id, kind, entry.arc.0, entry.arc.1);
+            }
+            Event::TrapCause | Event::TrapValue | Event::AsidChange | Event::ModeChange | Event::TaskSwitch => {
+                let kind = match entry.event {
+                    Event::TrapCause => 0,
+                    Event::TrapValue => 1,
+                    Event::AsidChange => 2,
+                    Event::ModeChange => 3,
+                    _ => 4,
+                };
+                self.kind_event(VALUE_ID, kind, entry.arc.0, entry.arc.1);
+            }
+        }
+        if StackUnwinder::steps_on(entry.event) {
+            let update = self.stack_unwinder.step(&entry);
+            for frame in update.closed.iter() {
+                self.func_exit(frame);
+            }
+            // frames opened by a jump or trap are entered from where it was taken
+            let call_site = if matches!(entry.event, Event::Start | Event::End | Event::TaskSwitch) { 0 } else { entry.arc.0 };
+            for frame in update.opened.iter() {
+                self.func_entry(frame, call_site);
+            }
+        }
+        if self.packet.len() >= PACKET_SIZE_LIMIT {
+            self.flush_packet();
+        }
+    }
+
+    fn _flush(&mut self) {
+        // close whatever is still open, if the trace ended without an End
+        for frame in self.stack_unwinder.flush().iter() {
+            self.func_exit(frame);
+        }
+        self.flush_packet();
+        self.writer.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stack_unwinder::SymbolInfo;
+
+    use indexmap::IndexMap;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const MAIN: u64 = 0x1000;
+
+    // the stream as written, kept after the receiver is done with it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn receiver(stream: &SharedBuf) -> CtfReceiver {
+        let mut func_symbol_map = IndexMap::new();
+        func_symbol_map.insert(MAIN, SymbolInfo { address: MAIN, name: "main".to_string(), mangled_name: "main".to_string(), index: 0, line: 0, file: String::new() });
+        CtfReceiver::with_unwinder(bus::Bus::new(1).add_rx(), Box::new(stream.clone()), StackUnwinder::from_maps(func_symbol_map, HashMap::new(), None))
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_packet() {
+        let stream = SharedBuf::default();
+        let mut receiver = receiver(&stream);
+        receiver._receive_entry(Entry::new_timed_event(Event::Start, 5, MAIN, 0));
+        let mut branch = Entry::new_timed_event(Event::TakenBranch, 7, MAIN + 0x10, MAIN + 0x40);
+        branch.context.mode = PrivMode::U;
+        receiver._receive_entry(branch);
+        receiver._flush();
+        let bytes = stream.0.lock().unwrap().clone();
+
+        // packet.header and packet.context
+        assert_eq!(PACKET_HEADER_SIZE, 52);
+        assert_eq!(u32_at(&bytes, 0), CTF_MAGIC);
+        assert_eq!(u32_at(&bytes, 4), STREAM_ID);
+        assert_eq!(u64_at(&bytes, 8), 5);
+        assert_eq!(u64_at(&bytes, 16), 7);
+        assert_eq!(u64_at(&bytes, 24), bytes.len() as u64 * 8);
+        assert_eq!(u64_at(&bytes, 32), bytes.len() as u64 * 8);
+        assert_eq!(u64_at(&bytes, 40), 0);
+        assert_eq!(u32_at(&bytes, 48), CPU_ID);
+
+        // event.header is the id and the timestamp, event.context the mode
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&FUNC_ENTRY_ID.to_le_bytes());
+        expected.extend_from_slice(&5u64.to_le_bytes());
+        expected.push(3);
+        expected.extend_from_slice(&MAIN.to_le_bytes());
+        expected.extend_from_slice(&0u64.to_le_bytes());
+        expected.push(1);
+        expected.extend_from_slice(b"main\0");
+        expected.extend_from_slice(&BRANCH_ID.to_le_bytes());
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.push(0);
+        expected.push(0);
+        expected.extend_from_slice(&(MAIN + 0x10).to_le_bytes());
+        expected.extend_from_slice(&(MAIN + 0x40).to_le_bytes());
+        // closed when the trace ends
+        expected.extend_from_slice(&FUNC_EXIT_ID.to_le_bytes());
+        expected.extend_from_slice(&7u64.to_le_bytes());
+        expected.push(0);
+        expected.extend_from_slice(&MAIN.to_le_bytes());
+        expected.extend_from_slice(b"main\0");
+        assert_eq!(bytes[PACKET_HEADER_SIZE..], expected);
+    }
+
+    #[test]
+    fn test_packet_per_size_limit() {
+        let stream = SharedBuf::default();
+        let mut receiver = receiver(&stream);
+        // 13 bytes of header and context and 17 of fields per branch
+        let branches = PACKET_SIZE_LIMIT / 30 + 1;
+        for timestamp in 0..branches as u64 {
+            receiver._receive_entry(Entry::new_timed_event(Event::TakenBranch, timestamp, MAIN, MAIN + 0x10));
+        }
+        receiver._receive_entry(Entry::new_timed_event(Event::TakenBranch, branches as u64, MAIN, MAIN + 0x10));
+        receiver._flush();
+        let bytes = stream.0.lock().unwrap().clone();
+        let first_size = u64_at(&bytes, 32) as usize / 8;
+        assert_eq!(first_size, PACKET_HEADER_SIZE + branches * 30);
+        assert_eq!(u64_at(&bytes, 16), branches as u64 - 1);
+        let second = &bytes[first_size..];
+        assert_eq!(u32_at(second, 0), CTF_MAGIC);
+        assert_eq!(u64_at(second, 8), branches as u64);
+        assert_eq!(second.len(), PACKET_HEADER_SIZE + 30);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = metadata(250_000_000);
+        assert!(metadata.starts_with("/* CTF 1.8 */"));
+        assert!(metadata.contains("freq = 250000000;"));
+        assert!(!metadata.contains('@'));
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use object::{Object, ObjectSymbol};
+
+use log::debug;
+use anyhow::Result;
+
+// locate the file that carries DWARF and symbols for a (possibly stripped) binary
+// search order follows gdb: the binary itself, the build-id tree, then .gnu_debuglink
+pub fn find_debug_file(elf_path: &str, debug_dirs: &[String]) -> Result<String> {
+    let elf_data = fs::read(elf_path)?;
+    let elf = object::File::parse(&*elf_data)?;
+    let build_id = elf.build_id()?.map(|id| id.to_vec());
+
+    if elf.section_by_name(".debug_info").is_some() {
+        debug!("[debug_info] {} carries its own debug info", elf_path);
+        return Ok(elf_path.to_string());
+    }
+
+    // <debug-dir>/.build-id/ab/cdef....debug
+    if let Some(build_id) = build_id.as_ref().filter(|id| id.len() > 1) {
+        let hex: String = build_id.iter().map(|b| format!("{:02x}", b)).collect();
+        for debug_dir in debug_dirs {
+            let candidate = Path::new(debug_dir).join(".build-id").join(&hex[..2]).join(format!("{}.debug", &hex[2..]));
+            if candidate.is_file() {
+                debug!("[debug_info] found debug file by build-id: {}", candidate.display());
+                return Ok(candidate.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    if let Some((link_name, link_crc)) = elf.gnu_debuglink()? {
+        let link_name = String::from_utf8_lossy(link_name).to_string();
+        let elf_dir = Path::new(elf_path).parent().map(|p| p.to_path_buf()).unwrap_or_default();
+        let abs_elf_dir = fs::canonicalize(&elf_dir).unwrap_or(elf_dir.clone());
+        let mut candidates: Vec<PathBuf> = vec![elf_dir.join(&link_name), elf_dir.join(".debug").join(&link_name)];
+        for debug_dir in debug_dirs {
+            candidates.push(Path::new(debug_dir).join(&link_name));
+            candidates.push(Path::new(debug_dir).join(abs_elf_dir.strip_prefix("/").unwrap_or(&abs_elf_dir)).join(&link_name));
+        }
+        for candidate in candidates {
+            // the binary may be linked to itself
+            if !candidate.is_file() || fs::canonicalize(&candidate).ok() == fs::canonicalize(elf_path).ok() {
+                continue;
+            }
+            // a debug file from another build silently produces wrong symbols, so be loud about it
+            let debug_data = fs::read(&candidate)?;
+            if !build_id_matches(build_id.as_deref(), &debug_data)? {
+                eprintln!("WARNING: build-id of {} does not match debug file {}, skipping it", elf_path, candidate.display());
+                continue;
+            }
+            if crc32(&debug_data) != link_crc {
+                eprintln!("WARNING: {} does not match the .gnu_debuglink CRC of {}, skipping it", candidate.display(), elf_path);
+                continue;
+            }
+            debug!("[debug_info] found debug file by debuglink: {}", candidate.display());
+            return Ok(candidate.to_string_lossy().to_string());
+        }
+    }
+
+    eprintln!("WARNING: no debug info found for {}, source locations will be empty", elf_path);
+    Ok(elf_path.to_string())
+}
+
+// the file whose symbol table should be used: stripped binaries fall back to the debug file
+pub fn symbol_file(elf_path: &str, debug_path: &str) -> Result<String> {
+    let elf_data = fs::read(elf_path)?;
+    let elf = object::File::parse(&*elf_data)?;
+    if elf.symbols().any(|s| s.kind() == object::SymbolKind::Text) {
+        Ok(elf_path.to_string())
+    } else {
+        Ok(debug_path.to_string())
+    }
+}
+
+// a debug file without a build-id, or for a binary without one, cannot be checked
+fn build_id_matches(build_id: Option<&[u8]>, debug_data: &[u8]) -> Result<bool> {
+    let debug_elf = object::File::parse(debug_data)?;
+    match (build_id, debug_elf.build_id()?) {
+        (Some(build_id), Some(debug_build_id)) => Ok(build_id == debug_build_id),
+        _ => Ok(true),
+    }
+}
+
+// CRC-32 (IEEE) as used by .gnu_debuglink
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHT_STRTAB: u32 = 3;
+    const SHT_NOTE: u32 = 7;
+    const SHT_PROGBITS: u32 = 1;
+
+    // a relocatable RISC-V ELF with only the given sections
+    fn elf(sections: &[(&str, u32, Vec<u8>)]) -> Vec<u8> {
+        let mut shstrtab = vec![0u8];
+        let mut names = Vec::new();
+        for (name, _, _) in sections.iter().chain([(".shstrtab", SHT_STRTAB, Vec::new())].iter()) {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        let mut data = vec![0u8; 64];
+        let mut headers = vec![[0u8; 64]];
+        let contents = sections.iter().map(|(_, kind, content)| (*kind, content.clone())).chain([(SHT_STRTAB, shstrtab)]);
+        for ((kind, content), name) in contents.zip(names) {
+            data.resize(data.len().next_multiple_of(8), 0);
+            let mut header = [0u8; 64];
+            header[0..4].copy_from_slice(&name.to_le_bytes());
+            header[4..8].copy_from_slice(&kind.to_le_bytes());
+            header[24..32].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            header[32..40].copy_from_slice(&(content.len() as u64).to_le_bytes());
+            header[48..56].copy_from_slice(&4u64.to_le_bytes());
+            headers.push(header);
+            data.extend_from_slice(&content);
+        }
+        data.resize(data.len().next_multiple_of(8), 0);
+        let shoff = data.len() as u64;
+        for header in headers.iter() {
+            data.extend_from_slice(header);
+        }
+        // ELFCLASS64, little endian, ET_REL, EM_RISCV
+        data[0..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        data[16..18].copy_from_slice(&1u16.to_le_bytes());
+        data[18..20].copy_from_slice(&243u16.to_le_bytes());
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[40..48].copy_from_slice(&shoff.to_le_bytes());
+        data[52..54].copy_from_slice(&64u16.to_le_bytes());
+        data[58..60].copy_from_slice(&64u16.to_le_bytes());
+        data[60..62].copy_from_slice(&(headers.len() as u16).to_le_bytes());
+        data[62..64].copy_from_slice(&(headers.len() as u16 - 1).to_le_bytes());
+        data
+    }
+
+    fn build_id_note(id: &[u8]) -> (&'static str, u32, Vec<u8>) {
+        let mut note = Vec::new();
+        note.extend_from_slice(&4u32.to_le_bytes());
+        note.extend_from_slice(&(id.len() as u32).to_le_bytes());
+        // NT_GNU_BUILD_ID
+        note.extend_from_slice(&3u32.to_le_bytes());
+        note.extend_from_slice(b"GNU\0");
+        note.extend_from_slice(id);
+        note.resize(note.len().next_multiple_of(4), 0);
+        (".note.gnu.build-id", SHT_NOTE, note)
+    }
+
+    fn debuglink(name: &str, crc: u32) -> (&'static str, u32, Vec<u8>) {
+        let mut link = name.as_bytes().to_vec();
+        link.push(0);
+        link.resize(link.len().next_multiple_of(4), 0);
+        link.extend_from_slice(&crc.to_le_bytes());
+        (".gnu_debuglink", SHT_PROGBITS, link)
+    }
+
+    fn debug_file(id: &[u8]) -> Vec<u8> {
+        elf(&[build_id_note(id), (".debug_info", SHT_PROGBITS, vec![0; 4])])
+    }
+
+    // an empty directory of its own for each test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ltrace-decoder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn path(path: &Path) -> String {
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_build_id_matches() {
+        let debug_data = debug_file(&[0xab, 0xcd, 0xef]);
+        assert!(build_id_matches(Some(&[0xab, 0xcd, 0xef]), &debug_data).unwrap());
+        assert!(!build_id_matches(Some(&[0xab, 0xcd, 0x00]), &debug_data).unwrap());
+        // nothing to check against
+        assert!(build_id_matches(None, &debug_data).unwrap());
+        assert!(build_id_matches(Some(&[0xab]), &elf(&[])).unwrap());
+    }
+
+    #[test]
+    fn test_build_id_tree() {
+        let dir = scratch_dir("build-id");
+        let id = [0xab, 0xcd, 0xef, 0x01];
+        let elf_path = dir.join("prog");
+        fs::write(&elf_path, elf(&[build_id_note(&id)])).unwrap();
+        let debug_path = dir.join("usr/lib/debug/.build-id/ab/cdef01.debug");
+        fs::create_dir_all(debug_path.parent().unwrap()).unwrap();
+        fs::write(&debug_path, debug_file(&id)).unwrap();
+        let debug_dirs = [path(&dir.join("nowhere")), path(&dir.join("usr/lib/debug"))];
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&debug_path));
+        // a binary with debug info is its own debug file
+        fs::write(&elf_path, debug_file(&id)).unwrap();
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&elf_path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_debuglink_order() {
+        let dir = scratch_dir("debuglink");
+        let id = [0x12, 0x34];
+        let debug_data = debug_file(&id);
+        let elf_path = dir.join("bin/prog");
+        fs::create_dir_all(dir.join("bin/.debug")).unwrap();
+        fs::write(&elf_path, elf(&[build_id_note(&id), debuglink("prog.debug", crc32(&debug_data))])).unwrap();
+        let global_dir = dir.join("debug");
+        let global_path = global_dir.join("prog.debug");
+        fs::create_dir_all(&global_dir).unwrap();
+        fs::write(&global_path, &debug_data).unwrap();
+        let debug_dirs = [path(&global_dir)];
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&global_path));
+        // next to the binary comes before .debug, which comes before the debug dirs
+        let dot_debug_path = dir.join("bin/.debug/prog.debug");
+        fs::write(&dot_debug_path, &debug_data).unwrap();
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&dot_debug_path));
+        let local_path = dir.join("bin/prog.debug");
+        fs::write(&local_path, &debug_data).unwrap();
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&local_path));
+        // a file from another build is skipped, by build-id and by CRC
+        fs::write(&local_path, debug_file(&[0x56, 0x78])).unwrap();
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&dot_debug_path));
+        let mut corrupt = debug_data.clone();
+        corrupt.push(0);
+        fs::write(&dot_debug_path, corrupt).unwrap();
+        assert_eq!(find_debug_file(&path(&elf_path), &debug_dirs).unwrap(), path(&global_path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,10 @@
 use capstone::Insn;
-use crate::frontend::packet::TrapType;
+use crate::frontend::packet::{TrapType, ValType};
 use serde::Serialize;
+use std::fmt;
+use anyhow::{anyhow, Result};
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum Event {
     None,
     Start,
@@ -14,6 +16,11 @@ pub enum Event {
     TrapException,
     TrapInterrupt,
     TrapReturn,
+    TrapCause,
+    TrapValue,
+    AsidChange,
+    ModeChange,
+    TaskSwitch,
 }
 
 impl Event {
@@ -26,6 +33,17 @@ impl Event {
         }
     }
 
+    pub fn from_val_type(val_type: ValType) -> Self {
+        match val_type {
+            ValType::VCause => Event::TrapCause,
+            ValType::VTval => Event::TrapValue,
+            ValType::VAsid => Event::AsidChange,
+            ValType::VMode => Event::ModeChange,
+            ValType::VTask => Event::TaskSwitch,
+            ValType::VNone => panic!("VNone should not be converted to Event"),
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match self {
             Event::None => "None".to_string(),
@@ -38,6 +56,173 @@ impl Event {
             Event::TrapException => "TrapException".to_string(),
             Event::TrapInterrupt => "TrapInterrupt".to_string(),
             Event::TrapReturn => "TrapReturn".to_string(),
+            Event::TrapCause => "TrapCause".to_string(),
+            Event::TrapValue => "TrapValue".to_string(),
+            Event::AsidChange => "AsidChange".to_string(),
+            Event::ModeChange => "ModeChange".to_string(),
+            Event::TaskSwitch => "TaskSwitch".to_string(),
+        }
+    }
+}
+
+// what a jump means for the call stack, derived from the link-register
+// conventions of the RISC-V spec (x1/ra and x5/t0 are link registers)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ControlFlowKind {
+    None,          // not a jump, e.g. branches and traps
+    Jump,          // direct jump without link inside a function
+    Call,          // direct jump with rd = link
+    IndirectCall,  // jalr with rd = link, rs1 != link
+    Return,        // jalr with rd != link, rs1 = link
+    TailCall,      // jump without link to a function entry
+    IndirectJump,  // jalr without link, e.g. jump tables
+    CoroutineSwap, // jalr with rd = link, rs1 = link, rd != rs1
+}
+
+fn is_link_register(reg: &str) -> bool {
+    matches!(reg, "ra" | "x1" | "t0" | "x5")
+}
+
+impl ControlFlowKind {
+    // classify a jump instruction from its capstone mnemonic and operands
+    // to_func_entry tells whether the jump lands on the start of a function
+    pub fn from_jump(mnemonic: &str, op_str: &str, to_func_entry: bool) -> Self {
+        let ops: Vec<&str> = op_str.split(',').map(|op| op.trim()).filter(|op| !op.is_empty()).collect();
+        // (rd, rs1), rs1 is None for direct jumps
+        let (rd, rs1): (&str, Option<&str>) = match mnemonic {
+            "jal" | "c.jal" | "call" => (if ops.len() > 1 { ops[0] } else { "ra" }, None),
+            "j" | "c.j" | "tail" => ("zero", None),
+            "ret" => ("zero", Some("ra")),
+            "jr" | "c.jr" => ("zero", ops.first().copied()),
+            "c.jalr" => ("ra", ops.first().copied()),
+            "jalr" => match ops.len() {
+                // jalr rs1
+                1 => ("ra", Some(ops[0])),
+                // jalr rd, rs1, imm or jalr rd, imm(rs1)
+                _ => {
+                    let rs1 = if ops.len() == 2 {
+                        ops[1].split('(').nth(1).map(|r| r.trim_end_matches(')')).unwrap_or(ops[1])
+                    } else {
+                        ops[1]
+                    };
+                    (ops[0], Some(rs1))
+                }
+            },
+            _ => return ControlFlowKind::None,
+        };
+        let rd_link = is_link_register(rd);
+        match rs1 {
+            None if rd_link => ControlFlowKind::Call,
+            None if to_func_entry => ControlFlowKind::TailCall,
+            None => ControlFlowKind::Jump,
+            Some(rs1) => match (rd_link, is_link_register(rs1)) {
+                (false, false) if to_func_entry => ControlFlowKind::TailCall,
+                (false, false) => ControlFlowKind::IndirectJump,
+                (false, true) => ControlFlowKind::Return,
+                (true, false) => ControlFlowKind::IndirectCall,
+                (true, true) if rd == rs1 => ControlFlowKind::IndirectCall,
+                (true, true) => ControlFlowKind::CoroutineSwap,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ControlFlowKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ControlFlowKind::None => "None",
+            ControlFlowKind::Jump => "Jump",
+            ControlFlowKind::Call => "Call",
+            ControlFlowKind::IndirectCall => "IndirectCall",
+            ControlFlowKind::Return => "Return",
+            ControlFlowKind::TailCall => "TailCall",
+            ControlFlowKind::IndirectJump => "IndirectJump",
+            ControlFlowKind::CoroutineSwap => "CoroutineSwap",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// privilege levels of the RISC-V privileged spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, clap::ValueEnum)]
+pub enum PrivMode {
+    U,
+    S,
+    #[default]
+    M,
+}
+
+impl PrivMode {
+    // from the level encoding of mstatus.MPP, 0 = U, 1 = S, 3 = M
+    // 2 is reserved and anything else does not fit in MPP
+    pub fn from_level(level: u64) -> Result<Self> {
+        match level {
+            0 => Ok(PrivMode::U),
+            1 => Ok(PrivMode::S),
+            3 => Ok(PrivMode::M),
+            _ => Err(anyhow!("invalid privilege level: {}", level)),
+        }
+    }
+
+    pub fn level(&self) -> u64 {
+        match self {
+            PrivMode::U => 0,
+            PrivMode::S => 1,
+            PrivMode::M => 3,
+        }
+    }
+}
+
+impl fmt::Display for PrivMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PrivMode::U => "U",
+            PrivMode::S => "S",
+            PrivMode::M => "M",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// what profiles are kept apart by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SplitBy {
+    None,
+    Mode,
+    Asid,
+    Context,
+}
+
+// where the hart is executing, tracked by the frontend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+pub struct Context {
+    pub mode: PrivMode,
+    // none until an address space is reported
+    pub asid: Option<u64>,
+}
+
+impl Context {
+    // what tells profiles apart, none if they are not split
+    // the label is used in profile and file names, so it has no spaces
+    pub fn key(&self, split_by: SplitBy) -> Option<String> {
+        let asid = match self.asid {
+            Some(asid) => format!("asid-{}", asid),
+            None => String::from("no-asid"),
+        };
+        match split_by {
+            SplitBy::None => None,
+            SplitBy::Mode => Some(format!("{}-mode", self.mode)),
+            SplitBy::Asid => Some(asid),
+            SplitBy::Context => Some(format!("{}-mode.{}", self.mode, asid)),
+        }
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.asid {
+            Some(asid) => write!(f, "{}-mode, asid {}", self.mode, asid),
+            None => write!(f, "{}-mode", self.mode),
         }
     }
 }
@@ -45,24 +230,105 @@ impl Event {
 #[derive(Debug, Clone, Serialize)]
 pub struct Entry {
     pub event: Event,
+    pub cf_kind: ControlFlowKind,
     pub arc: (u64, u64), // from, to
     pub insn_bytes: Vec<u8>,
     pub insn_mnemonic: Option<String>,
     pub insn_op_str: Option<String>,
     pub insn_len: usize,
     pub timestamp: Option<u64>,
+    // stamped by the frontend, traps carry the context they enter
+    pub context: Context,
 }
 
 impl Entry {
     pub fn new_timed_event(event: Event, timestamp: u64, from: u64, to: u64) -> Self {
-        Self { event, arc: (from, to), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp) }
+        Self { event, cf_kind: ControlFlowKind::None, arc: (from, to), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp), context: Context::default() }
+    }
+
+    pub fn new_timed_jump(event: Event, cf_kind: ControlFlowKind, timestamp: u64, from: u64, to: u64) -> Self {
+        Self { event, cf_kind, arc: (from, to), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp), context: Context::default() }
     }
 
     pub fn new_insn(insn: &Insn) -> Self {
-        Self { event: Event::None, arc: (insn.address(), 0), insn_bytes: insn.bytes().to_vec(), insn_mnemonic: Some(insn.mnemonic().unwrap().to_string()), insn_op_str: Some(insn.op_str().unwrap().to_string()), insn_len: insn.len(), timestamp: None }
+        Self { event: Event::None, cf_kind: ControlFlowKind::None, arc: (insn.address(), 0), insn_bytes: insn.bytes().to_vec(), insn_mnemonic: Some(insn.mnemonic().unwrap().to_string()), insn_op_str: Some(insn.op_str().unwrap().to_string()), insn_len: insn.len(), timestamp: None, context: Context::default() }
     }
 
     pub fn new_timed_trap(trap_type: TrapType, timestamp: u64, from: u64, to: u64) -> Self {
-        Self { event: Event::from_trap_type(trap_type), arc: (from, to), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp) }
+        Self { event: Event::from_trap_type(trap_type), cf_kind: ControlFlowKind::None, arc: (from, to), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp), context: Context::default() }
+    }
+
+    // arc.1 carries the reported value
+    pub fn new_timed_value(val_type: ValType, timestamp: u64, pc: u64, value: u64) -> Self {
+        Self { event: Event::from_val_type(val_type), cf_kind: ControlFlowKind::None, arc: (pc, value), insn_bytes: vec![], insn_mnemonic: None, insn_op_str: None, insn_len: 0, timestamp: Some(timestamp), context: Context::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_jump_direct() {
+        assert_eq!(ControlFlowKind::from_jump("jal", "0x1000", true), ControlFlowKind::Call);
+        assert_eq!(ControlFlowKind::from_jump("jal", "ra, 0x1000", true), ControlFlowKind::Call);
+        assert_eq!(ControlFlowKind::from_jump("jal", "t0, 0x1000", true), ControlFlowKind::Call);
+        assert_eq!(ControlFlowKind::from_jump("jal", "x5, 0x1000", true), ControlFlowKind::Call);
+        assert_eq!(ControlFlowKind::from_jump("c.jal", "0x1000", true), ControlFlowKind::Call);
+        assert_eq!(ControlFlowKind::from_jump("call", "0x1000", true), ControlFlowKind::Call);
+        // jal without link is a plain jump, or a tail call if it lands on a function
+        assert_eq!(ControlFlowKind::from_jump("jal", "zero, 0x1000", false), ControlFlowKind::Jump);
+        assert_eq!(ControlFlowKind::from_jump("jal", "zero, 0x1000", true), ControlFlowKind::TailCall);
+        assert_eq!(ControlFlowKind::from_jump("jal", "a0, 0x1000", false), ControlFlowKind::Jump);
+        assert_eq!(ControlFlowKind::from_jump("j", "0x1000", false), ControlFlowKind::Jump);
+        assert_eq!(ControlFlowKind::from_jump("j", "0x1000", true), ControlFlowKind::TailCall);
+        assert_eq!(ControlFlowKind::from_jump("c.j", "0x1000", false), ControlFlowKind::Jump);
+        assert_eq!(ControlFlowKind::from_jump("c.j", "0x1000", true), ControlFlowKind::TailCall);
+        assert_eq!(ControlFlowKind::from_jump("tail", "0x1000", true), ControlFlowKind::TailCall);
+    }
+
+    #[test]
+    fn test_from_jump_indirect() {
+        // jalr rs1
+        assert_eq!(ControlFlowKind::from_jump("jalr", "a5", false), ControlFlowKind::IndirectCall);
+        // jalr rd, imm(rs1)
+        assert_eq!(ControlFlowKind::from_jump("jalr", "ra, 0(a5)", false), ControlFlowKind::IndirectCall);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "zero, 0(ra)", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "zero, 8(a5)", false), ControlFlowKind::IndirectJump);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "zero, 8(a5)", true), ControlFlowKind::TailCall);
+        // jalr rd, rs1, imm
+        assert_eq!(ControlFlowKind::from_jump("jalr", "ra, a5, 0", false), ControlFlowKind::IndirectCall);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "zero, t0, 0", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "x0, x1, 0", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "ra, ra, 0", false), ControlFlowKind::IndirectCall);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "t0, ra, 0", false), ControlFlowKind::CoroutineSwap);
+        assert_eq!(ControlFlowKind::from_jump("jalr", "ra, t0, 0", false), ControlFlowKind::CoroutineSwap);
+        // pseudo-instructions and compressed forms
+        assert_eq!(ControlFlowKind::from_jump("ret", "", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("jr", "ra", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("jr", "a5", false), ControlFlowKind::IndirectJump);
+        assert_eq!(ControlFlowKind::from_jump("jr", "a5", true), ControlFlowKind::TailCall);
+        assert_eq!(ControlFlowKind::from_jump("c.jr", "ra", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("c.jr", "t0", false), ControlFlowKind::Return);
+        assert_eq!(ControlFlowKind::from_jump("c.jr", "a5", false), ControlFlowKind::IndirectJump);
+        assert_eq!(ControlFlowKind::from_jump("c.jalr", "a5", false), ControlFlowKind::IndirectCall);
+        assert_eq!(ControlFlowKind::from_jump("c.jalr", "ra", false), ControlFlowKind::IndirectCall);
+        assert_eq!(ControlFlowKind::from_jump("c.jalr", "t0", false), ControlFlowKind::CoroutineSwap);
+    }
+
+    #[test]
+    fn test_priv_mode_levels() {
+        for mode in [PrivMode::U, PrivMode::S, PrivMode::M] {
+            assert_eq!(PrivMode::from_level(mode.level()).unwrap(), mode);
+        }
+        assert!(PrivMode::from_level(2).is_err());
+        assert!(PrivMode::from_level(7).is_err());
+    }
+
+    #[test]
+    fn test_from_jump_not_a_jump() {
+        assert_eq!(ControlFlowKind::from_jump("beq", "a0, a1, 0x1000", true), ControlFlowKind::None);
+        assert_eq!(ControlFlowKind::from_jump("c.beqz", "a0, 0x1000", false), ControlFlowKind::None);
+        assert_eq!(ControlFlowKind::from_jump("addi", "sp, sp, -16", false), ControlFlowKind::None);
     }
 }
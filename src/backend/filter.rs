@@ -0,0 +1,397 @@
+use crate::backend::event::{Context, Entry, Event, PrivMode};
+use crate::backend::abstract_receiver::AbstractReceiver;
+use crate::backend::stack_unwinder::StackUnwinder;
+
+use bus::BusReader;
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+// what a filter rule looks at
+#[derive(Debug, Clone)]
+pub enum FilterMatch {
+    // pc in [start, end)
+    AddrRange(u64, u64),
+    // pc inside a function whose name matches the glob
+    Symbol(String),
+    // any open frame matches the glob, i.e. the function and all it calls
+    Tree(String),
+    // timestamp in [start, end)
+    Time(u64, Option<u64>),
+    // running in a privilege mode
+    Mode(PrivMode),
+    // running in an address space
+    Asid(u64),
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterRule {
+    pub include: bool,
+    pub what: FilterMatch,
+}
+
+impl FilterRule {
+    // +0x1000-0x2000, -sym:idle_*, +tree:crypto_*, +time:100-200 (the end is optional),
+    // +mode:U, -asid:3
+    pub fn parse(rule: &str) -> Result<Self> {
+        let include = match rule.chars().next() {
+            Some('+') => true,
+            Some('-') => false,
+            _ => return Err(anyhow!("filter rule must start with + or -: {}", rule)),
+        };
+        let rule = &rule[1..];
+        let what = if let Some(glob) = rule.strip_prefix("sym:") {
+            FilterMatch::Symbol(glob.to_string())
+        } else if let Some(glob) = rule.strip_prefix("tree:") {
+            FilterMatch::Tree(glob.to_string())
+        } else if let Some(window) = rule.strip_prefix("time:") {
+            let (start, end) = window.split_once('-').ok_or_else(|| anyhow!("time window must be start-end: {}", window))?;
+            let end = if end.is_empty() { None } else { Some(end.parse()?) };
+            FilterMatch::Time(start.parse()?, end)
+        } else if let Some(mode) = rule.strip_prefix("mode:") {
+            let mode = match mode {
+                "U" | "u" => PrivMode::U,
+                "S" | "s" => PrivMode::S,
+                "M" | "m" => PrivMode::M,
+                _ => return Err(anyhow!("mode must be U, S or M: {}", mode)),
+            };
+            FilterMatch::Mode(mode)
+        } else if let Some(asid) = rule.strip_prefix("asid:") {
+            FilterMatch::Asid(asid.parse()?)
+        } else {
+            let (start, end) = rule.split_once('-').ok_or_else(|| anyhow!("address range must be start-end: {}", rule))?;
+            FilterMatch::AddrRange(parse_addr(start)?, parse_addr(end)?)
+        };
+        Ok(Self { include, what })
+    }
+}
+
+fn parse_addr(addr: &str) -> Result<u64> {
+    let hex = addr.strip_prefix("0x").ok_or_else(|| anyhow!("addresses must be hex (0x...): {}", addr))?;
+    Ok(u64::from_str_radix(hex, 16)?)
+}
+
+// receiver name -> rules, from receiver=rule,rule,...
+pub fn parse_filters(specs: &[String]) -> Result<HashMap<String, Vec<FilterRule>>> {
+    let mut filters: HashMap<String, Vec<FilterRule>> = HashMap::new();
+    for spec in specs {
+        let (name, rules) = spec.split_once('=').ok_or_else(|| anyhow!("filter must be receiver=rules: {}", spec))?;
+        for rule in rules.split(',') {
+            filters.entry(name.to_string()).or_default().push(FilterRule::parse(rule)?);
+        }
+    }
+    Ok(filters)
+}
+
+// shell-style glob with * and ?
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // position after the last *, and where in name it is retried from
+    let (mut g, mut n) = (0, 0);
+    let mut retry: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if g < glob.len() && (glob[g] == '?' || glob[g] == name[n]) {
+            g += 1;
+            n += 1;
+        } else if g < glob.len() && glob[g] == '*' {
+            retry = Some((g + 1, n));
+            g += 1;
+        } else if let Some((retry_g, retry_n)) = retry {
+            g = retry_g;
+            n = retry_n + 1;
+            retry = Some((retry_g, retry_n + 1));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+// sits between the bus and a receiver, forwarding only entries that pass its rules
+// like regions of interest, every stretch that passes is framed by a Start and
+// an End, carrying the number of the region it is in
+pub struct FilteredReceiver {
+    inner: Box<dyn AbstractReceiver>,
+    rules: Vec<FilterRule>,
+    // only needed by symbol and tree rules
+    stack_unwinder: Option<StackUnwinder>,
+    // function address -> whether it matches a glob, per rule
+    glob_cache: HashMap<(usize, u64), bool>,
+    active: bool,
+    region: u64,
+    last_timestamp: u64,
+    last_context: Context,
+}
+
+impl FilteredReceiver {
+    pub fn new(inner: Box<dyn AbstractReceiver>, rules: Vec<FilterRule>, elf_path: String, debug_path: String) -> Self {
+        let needs_symbols = rules.iter().any(|rule| matches!(rule.what, FilterMatch::Symbol(_) | FilterMatch::Tree(_)));
+        let stack_unwinder = if needs_symbols { Some(StackUnwinder::new(elf_path, debug_path).unwrap()) } else { None };
+        Self { inner, rules, stack_unwinder, glob_cache: HashMap::new(), active: false, region: 0, last_timestamp: 0, last_context: Context::default() }
+    }
+
+    fn func_matches(&mut self, rule_index: usize, glob: &str, func_addr: u64) -> bool {
+        let stack_unwinder = self.stack_unwinder.as_ref().unwrap();
+        *self.glob_cache.entry((rule_index, func_addr)).or_insert_with(|| {
+            let symbol = stack_unwinder.get_symbol_info(func_addr);
+            glob_match(glob, &symbol.name) || glob_match(glob, &symbol.mangled_name)
+        })
+    }
+
+    fn rule_matches(&mut self, rule_index: usize, pc: u64) -> bool {
+        match self.rules[rule_index].what.clone() {
+            FilterMatch::AddrRange(start, end) => pc >= start && pc < end,
+            FilterMatch::Time(start, end) => self.last_timestamp >= start && end.is_none_or(|end| self.last_timestamp < end),
+            FilterMatch::Mode(mode) => self.last_context.mode == mode,
+            FilterMatch::Asid(asid) => self.last_context.asid == Some(asid),
+            FilterMatch::Symbol(glob) => {
+                match self.stack_unwinder.as_ref().unwrap().func_containing(pc) {
+                    Some(func_addr) => self.func_matches(rule_index, &glob, func_addr),
+                    None => false,
+                }
+            }
+            FilterMatch::Tree(glob) => {
+                let func_addrs: Vec<u64> = self.stack_unwinder.as_ref().unwrap().frame_addrs().collect();
+                func_addrs.into_iter().any(|func_addr| self.func_matches(rule_index, &glob, func_addr))
+            }
+        }
+    }
+
+    // included by any include rule (or there are none) and excluded by no exclude rule
+    fn passes(&mut self, pc: u64) -> bool {
+        let mut has_include = false;
+        let mut included = false;
+        for rule_index in 0..self.rules.len() {
+            let include = self.rules[rule_index].include;
+            if include {
+                has_include = true;
+                if !included && self.rule_matches(rule_index, pc) {
+                    included = true;
+                }
+            } else if self.rule_matches(rule_index, pc) {
+                return false;
+            }
+        }
+        included || !has_include
+    }
+
+    fn update(&mut self, pc: u64) {
+        let passes = self.passes(pc);
+        if passes && !self.active {
+            debug!("[filter] passing from {:#x}", pc);
+            let mut start = Entry::new_timed_event(Event::Start, self.last_timestamp, pc, self.region);
+            start.context = self.last_context;
+            self.inner._receive_entry(start);
+            self.active = true;
+        } else if !passes && self.active {
+            debug!("[filter] blocking from {:#x}", pc);
+            let mut end = Entry::new_timed_event(Event::End, self.last_timestamp, pc, self.region);
+            end.context = self.last_context;
+            self.inner._receive_entry(end);
+            self.active = false;
+        }
+    }
+}
+
+impl AbstractReceiver for FilteredReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        self.inner.bus_rx()
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.inner._bump_checksum();
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = timestamp;
+        }
+        self.last_context = entry.context;
+        if let Some(stack_unwinder) = self.stack_unwinder.as_mut() {
+            if StackUnwinder::steps_on(entry.event) {
+                stack_unwinder.step(&entry);
+            }
+        }
+        match entry.event {
+            Event::Start => {
+                self.region = entry.arc.1;
+                self.update(entry.arc.0);
+            }
+            Event::End => {
+                if self.active {
+                    self.inner._receive_entry(entry);
+                    self.active = false;
+                }
+            }
+            Event::None => {
+                self.update(entry.arc.0);
+                if self.active {
+                    self.inner._receive_entry(entry);
+                }
+            }
+            _ => {
+                if self.active {
+                    self.inner._receive_entry(entry);
+                }
+            }
+        }
+    }
+
+    fn _flush(&mut self) {
+        self.inner._flush();
+    }
+}
+
+// wrap a receiver in the filter configured for it, taking the filter out of filters
+pub fn with_filter(name: &str, receiver: Box<dyn AbstractReceiver>, filters: &mut HashMap<String, Vec<FilterRule>>, elf_path: &str, debug_path: &str) -> Box<dyn AbstractReceiver> {
+    match filters.remove(name) {
+        Some(rules) => Box::new(FilteredReceiver::new(receiver, rules, elf_path.to_string(), debug_path.to_string())),
+        None => receiver,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::event::ControlFlowKind;
+    use crate::backend::stack_unwinder::SymbolInfo;
+
+    use indexmap::IndexMap;
+    use std::sync::{Arc, Mutex};
+
+    const MAIN: u64 = 0x1000;
+    const CRYPTO_INIT: u64 = 0x1100;
+    const HELPER: u64 = 0x1200;
+
+    // what passed the filter, by event and pc
+    type Passed = Arc<Mutex<Vec<(Event, u64)>>>;
+
+    struct Recorder {
+        bus_rx: BusReader<Entry>,
+        entries: Passed,
+    }
+
+    impl AbstractReceiver for Recorder {
+        fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+            &mut self.bus_rx
+        }
+
+        fn _bump_checksum(&mut self) {}
+
+        fn _receive_entry(&mut self, entry: Entry) {
+            self.entries.lock().unwrap().push((entry.event, entry.arc.0));
+        }
+
+        fn _flush(&mut self) {}
+    }
+
+    fn filtered(rules: &[&str]) -> (FilteredReceiver, Passed) {
+        let mut func_symbol_map = IndexMap::new();
+        for (index, (address, name)) in [(MAIN, "main"), (CRYPTO_INIT, "crypto_init"), (HELPER, "helper")].into_iter().enumerate() {
+            func_symbol_map.insert(address, SymbolInfo { address, name: name.to_string(), mangled_name: name.to_string(), index: index as u32, line: 0, file: String::new() });
+        }
+        let entries = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder { bus_rx: bus::Bus::new(1).add_rx(), entries: entries.clone() };
+        let filtered = FilteredReceiver {
+            inner: Box::new(recorder),
+            rules: rules.iter().map(|rule| FilterRule::parse(rule).unwrap()).collect(),
+            stack_unwinder: Some(StackUnwinder::from_maps(func_symbol_map, std::collections::HashMap::new(), None)),
+            glob_cache: HashMap::new(),
+            active: false,
+            region: 0,
+            last_timestamp: 0,
+            last_context: Context::default(),
+        };
+        (filtered, entries)
+    }
+
+    fn insn(pc: u64, context: Context) -> Entry {
+        let mut entry = Entry::new_timed_event(Event::None, 0, pc, 0);
+        entry.timestamp = None;
+        entry.context = context;
+        entry
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("crypto_*", "crypto_init"));
+        assert!(glob_match("*_init", "crypto_init"));
+        assert!(glob_match("c?ypto_*t", "crypto_init"));
+        assert!(glob_match("*to_in*", "crypto_init"));
+        assert!(glob_match("main", "main"));
+        assert!(!glob_match("crypto_*", "main"));
+        assert!(!glob_match("*_init", "crypto_init2"));
+        assert!(!glob_match("ma?", "main"));
+        assert!(!glob_match("main", "mai"));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(FilterRule::parse("+0x1000-0x2000").unwrap(), FilterRule { include: true, what: FilterMatch::AddrRange(0x1000, 0x2000) }));
+        assert!(matches!(FilterRule::parse("-time:100-").unwrap(), FilterRule { include: false, what: FilterMatch::Time(100, None) }));
+        assert!(matches!(FilterRule::parse("+mode:s").unwrap().what, FilterMatch::Mode(PrivMode::S)));
+        assert!(matches!(FilterRule::parse("-asid:7").unwrap().what, FilterMatch::Asid(7)));
+        assert!(FilterRule::parse("mode:U").is_err());
+        assert!(FilterRule::parse("+mode:H").is_err());
+        assert!(FilterRule::parse("+asid:x").is_err());
+        assert!(FilterRule::parse("+1000-2000").is_err());
+    }
+
+    #[test]
+    fn test_tree() {
+        let (mut filtered, entries) = filtered(&["+tree:crypto_*", "-sym:helper"]);
+        let context = Context::default();
+        filtered._receive_entry(Entry::new_timed_event(Event::Start, 0, MAIN, 0));
+        filtered._receive_entry(insn(MAIN + 0x4, context));
+        filtered._receive_entry(Entry::new_timed_jump(Event::InferrableJump, ControlFlowKind::Call, 1, MAIN + 0x8, CRYPTO_INIT));
+        filtered._receive_entry(insn(CRYPTO_INIT, context));
+        filtered._receive_entry(Entry::new_timed_jump(Event::InferrableJump, ControlFlowKind::Call, 2, CRYPTO_INIT + 0x4, MAIN + 0x40));
+        // main called from crypto_init is in its tree
+        filtered._receive_entry(insn(MAIN + 0x40, context));
+        filtered._receive_entry(Entry::new_timed_jump(Event::InferrableJump, ControlFlowKind::Call, 3, MAIN + 0x44, HELPER));
+        filtered._receive_entry(insn(HELPER, context));
+        filtered._receive_entry(Entry::new_timed_jump(Event::UninferableJump, ControlFlowKind::Return, 4, HELPER + 0x4, MAIN + 0x48));
+        filtered._receive_entry(insn(MAIN + 0x48, context));
+        filtered._receive_entry(Entry::new_timed_jump(Event::UninferableJump, ControlFlowKind::Return, 5, MAIN + 0x4c, CRYPTO_INIT + 0x8));
+        filtered._receive_entry(Entry::new_timed_jump(Event::UninferableJump, ControlFlowKind::Return, 6, CRYPTO_INIT + 0x8, MAIN + 0xc));
+        filtered._receive_entry(insn(MAIN + 0xc, context));
+        assert_eq!(*entries.lock().unwrap(), [
+            (Event::Start, CRYPTO_INIT),
+            (Event::None, CRYPTO_INIT),
+            (Event::InferrableJump, CRYPTO_INIT + 0x4),
+            (Event::None, MAIN + 0x40),
+            (Event::InferrableJump, MAIN + 0x44),
+            (Event::End, HELPER),
+            (Event::Start, MAIN + 0x48),
+            (Event::None, MAIN + 0x48),
+            (Event::UninferableJump, MAIN + 0x4c),
+            (Event::UninferableJump, CRYPTO_INIT + 0x8),
+            (Event::End, MAIN + 0xc),
+        ]);
+    }
+
+    #[test]
+    fn test_context() {
+        let (mut filtered, entries) = filtered(&["+mode:U", "-asid:3"]);
+        let user = |asid| Context { mode: PrivMode::U, asid: Some(asid) };
+        filtered._receive_entry(Entry::new_timed_event(Event::Start, 0, MAIN, 0));
+        filtered._receive_entry(insn(MAIN, Context::default()));
+        filtered._receive_entry(insn(MAIN + 0x4, user(1)));
+        filtered._receive_entry(insn(MAIN + 0x8, user(3)));
+        filtered._receive_entry(insn(MAIN + 0xc, user(1)));
+        filtered._receive_entry(insn(MAIN + 0x10, Context { mode: PrivMode::S, asid: Some(1) }));
+        assert_eq!(*entries.lock().unwrap(), [
+            (Event::Start, MAIN + 0x4),
+            (Event::None, MAIN + 0x4),
+            (Event::End, MAIN + 0x8),
+            (Event::Start, MAIN + 0xc),
+            (Event::None, MAIN + 0xc),
+            (Event::End, MAIN + 0x10),
+        ]);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::backend::event::{Entry, Event, PrivMode};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackUpdate};
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use log::debug;
+
+// the decoder follows a single hart, it is the only thread in the profile
+const HART_TID: u64 = 0;
+
+// gecko profile format version, the profiler upgrades it on load
+const GECKO_VERSION: u64 = 27;
+
+// marker phases
+const PHASE_INSTANT: u64 = 0;
+const PHASE_INTERVAL: u64 = 1;
+
+// categories, one per privilege mode and one for markers
+const CATEGORIES: [(&str, &str); 4] = [("U-mode", "green"), ("S-mode", "blue"), ("M-mode", "orange"), ("Trace", "grey")];
+const TRACE_CATEGORY: u64 = 3;
+
+fn category_of(mode: PrivMode) -> u64 {
+    match mode {
+        PrivMode::U => 0,
+        PrivMode::S => 1,
+        PrivMode::M => 2,
+    }
+}
+
+// the gecko profile format, as read by the Firefox Profiler
+// samples are taken every interval ticks from the exact stacks, traps and the
+// regions of interest with the gaps between them are markers
+pub struct FirefoxReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // timestamp ticks per second
+    clock_hz: u64,
+    // ticks between two samples
+    interval: u64,
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    // (function, category) -> frame index, and the frame table rows
+    frame_ids: HashMap<(u64, u64), u64>,
+    frames: Vec<Value>,
+    // (prefix, frame) -> stack index, and the stack table rows
+    stack_ids: HashMap<(Option<u64>, u64), u64>,
+    stacks: Vec<Value>,
+    samples: Vec<Value>,
+    markers: Vec<Value>,
+    // the category of each open frame, the mode it was opened in, outermost first
+    frame_categories: Vec<u64>,
+    curr_stack: Option<u64>,
+    // the first sample time and the next one to take, none between regions
+    first_sample: Option<u64>,
+    next_sample: Option<u64>,
+    // the open region and when it started
+    region: Option<(u64, u64)>,
+    last_end: Option<u64>,
+    // where the last trap marker is, to add its cause and value to
+    last_trap: Option<usize>,
+    last_timestamp: u64,
+}
+
+impl FirefoxReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, clock_hz: u64, interval: u64) -> Self {
+        debug!("FirefoxReceiver::new");
+        Self {
+            writer: BufWriter::new(File::create("trace.firefox.json").unwrap()),
+            receiver: BusReceiver {
+                name: "firefox".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path, debug_path).unwrap(),
+            clock_hz,
+            interval,
+            strings: Vec::new(),
+            string_ids: HashMap::new(),
+            frame_ids: HashMap::new(),
+            frames: Vec::new(),
+            stack_ids: HashMap::new(),
+            stacks: Vec::new(),
+            samples: Vec::new(),
+            markers: Vec::new(),
+            frame_categories: Vec::new(),
+            curr_stack: None,
+            first_sample: None,
+            next_sample: None,
+            region: None,
+            last_end: None,
+            last_trap: None,
+            last_timestamp: 0,
+        }
+    }
+
+    fn to_ms(&self, timestamp: u64) -> f64 {
+        timestamp as f64 * 1e3 / self.clock_hz as f64
+    }
+
+    fn string_id(&mut self, value: &str) -> u64 {
+        if let Some(id) = self.string_ids.get(value) {
+            return *id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(value.to_string());
+        self.string_ids.insert(value.to_string(), id);
+        id
+    }
+
+    fn frame_id(&mut self, func: u64, category: u64) -> u64 {
+        if let Some(id) = self.frame_ids.get(&(func, category)) {
+            return *id;
+        }
+        let symbol = self.stack_unwinder.get_symbol_info(func);
+        let location = if symbol.file.is_empty() { symbol.name.clone() } else { format!("{} ({}:{})", symbol.name, symbol.file, symbol.line) };
+        let location = self.string_id(&location);
+        let line = if symbol.line > 0 { json!(symbol.line) } else { Value::Null };
+        // location, relevantForJS, innerWindowID, implementation, line, column, category, subcategory
+        self.frames.push(json!([location, false, 0, null, line, null, category, 0]));
+        let id = self.frames.len() as u64 - 1;
+        self.frame_ids.insert((func, category), id);
+        id
+    }
+
+    fn stack_id(&mut self, prefix: Option<u64>, frame: u64) -> u64 {
+        if let Some(id) = self.stack_ids.get(&(prefix, frame)) {
+            return *id;
+        }
+        self.stacks.push(json!([prefix, frame]));
+        let id = self.stacks.len() as u64 - 1;
+        self.stack_ids.insert((prefix, frame), id);
+        id
+    }
+
+    // keep the frame categories in step with the unwinder and intern the new stack
+    fn apply(&mut self, update: &StackUpdate, mode: PrivMode) {
+        for _ in update.closed.iter() {
+            self.frame_categories.pop();
+        }
+        for _ in update.opened.iter() {
+            self.frame_categories.push(category_of(mode));
+        }
+        let stack = self.stack_unwinder.stack();
+        let mut curr_stack = None;
+        for (frame, category) in stack.iter().zip(self.frame_categories.clone()) {
+            let frame_id = self.frame_id(frame.symbol.address, category);
+            curr_stack = Some(self.stack_id(curr_stack, frame_id));
+        }
+        self.curr_stack = curr_stack;
+    }
+
+    // sample the current stack at every sample time before timestamp
+    fn sample_until(&mut self, timestamp: u64) {
+        let Some(mut next_sample) = self.next_sample else { return };
+        while next_sample < timestamp {
+            // stack, time, eventDelay
+            self.samples.push(json!([self.curr_stack, self.to_ms(next_sample), 0]));
+            next_sample += self.interval;
+        }
+        self.next_sample = Some(next_sample);
+    }
+
+    fn marker(&mut self, name: &str, start: u64, end: Option<u64>, data: Value) -> usize {
+        let name = self.string_id(name);
+        let phase = if end.is_some() { PHASE_INTERVAL } else { PHASE_INSTANT };
+        // name, startTime, endTime, phase, category, data
+        self.markers.push(json!([name, self.to_ms(start), end.map(|end| self.to_ms(end)), phase, TRACE_CATEGORY, data]));
+        self.markers.len() - 1
+    }
+
+    fn trap_marker(&mut self, entry: &Entry) {
+        let timestamp = entry.timestamp.unwrap();
+        let (name, target) = match entry.event {
+            Event::TrapException => ("exception", "handler"),
+            Event::TrapInterrupt => ("interrupt", "handler"),
+            _ => ("trap return", "target"),
+        };
+        let data = json!({"type": "Trap", "pc": format!("{:#x}", entry.arc.0), target: format!("{:#x}", entry.arc.1), "mode": format!("{}-mode", entry.context.mode)});
+        let index = self.marker(name, timestamp, None, data);
+        self.last_trap = (entry.event != Event::TrapReturn).then_some(index);
+    }
+
+    fn start_region(&mut self, region: u64, timestamp: u64) {
+        if let Some(last_end) = self.last_end.take() {
+            self.marker("gap", last_end, Some(timestamp), json!({"type": "Gap"}));
+        }
+        self.region = Some((region, timestamp));
+        // samples stay on the grid of the first region
+        let first_sample = *self.first_sample.get_or_insert(timestamp);
+        let skipped = timestamp.saturating_sub(first_sample).div_ceil(self.interval);
+        self.next_sample = Some(first_sample + skipped * self.interval);
+    }
+
+    fn end_region(&mut self, timestamp: u64) {
+        self.sample_until(timestamp);
+        if let Some((region, start)) = self.region.take() {
+            self.marker(&format!("region {}", region), start, Some(timestamp), json!({"type": "Region", "region": region}));
+        }
+        self.next_sample = None;
+        self.last_end = Some(timestamp);
+    }
+
+    fn meta(&self) -> Value {
+        let categories: Vec<Value> = CATEGORIES.iter().map(|(name, color)| json!({"name": name, "color": color, "subcategories": ["Other"]})).collect();
+        let trap_fields = ["pc", "handler", "target", "mode", "cause", "tval"].iter().map(|key| json!({"key": key, "label": key, "format": "string"})).collect::<Vec<Value>>();
+        json!({
+            "interval": self.to_ms(self.interval),
+            "startTime": 0,
+            "shutdownTime": null,
+            "processType": 0,
+            "product": "ltrace-decoder",
+            "stackwalk": 1,
+            "debug": 0,
+            "version": GECKO_VERSION,
+            "categories": categories,
+            "markerSchema": [
+                {"name": "Trap", "display": ["marker-chart", "marker-table", "timeline-overview"], "tooltipLabel": "{marker.name} at {marker.data.pc}", "data": trap_fields},
+                {"name": "Region", "display": ["marker-chart", "marker-table", "timeline-overview"], "data": [{"key": "region", "label": "region", "format": "integer"}]},
+                {"name": "Gap", "display": ["marker-chart", "marker-table", "timeline-overview"], "data": []},
+            ],
+        })
+    }
+
+    fn thread(&self) -> Value {
+        json!({
+            "name": format!("hart {}", HART_TID),
+            "processType": "default",
+            "processName": "ltrace-decoder",
+            "pid": 0,
+            "tid": HART_TID,
+            "registerTime": 0,
+            "unregisterTime": null,
+            "samples": {"schema": {"stack": 0, "time": 1, "eventDelay": 2}, "data": self.samples},
+            "markers": {"schema": {"name": 0, "startTime": 1, "endTime": 2, "phase": 3, "category": 4, "data": 5}, "data": self.markers},
+            "stackTable": {"schema": {"prefix": 0, "frame": 1}, "data": self.stacks},
+            "frameTable": {"schema": {"location": 0, "relevantForJS": 1, "innerWindowID": 2, "implementation": 3, "line": 4, "column": 5, "category": 6, "subcategory": 7}, "data": self.frames},
+            "stringTable": self.strings,
+        })
+    }
+}
+
+impl AbstractReceiver for FirefoxReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = timestamp;
+        }
+        match entry.event {
+            Event::Start => {
+                // regions of interest are numbered in arc.1
+                self.start_region(entry.arc.1, entry.timestamp.unwrap());
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.context.mode);
+            }
+            Event::End => {
+                self.end_region(entry.timestamp.unwrap());
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.context.mode);
+            }
+            event if StackUnwinder::steps_on(event) => {
+                self.sample_until(entry.timestamp.unwrap());
+                if matches!(entry.event, Event::TrapException | Event::TrapInterrupt | Event::TrapReturn) {
+                    self.trap_marker(&entry);
+                }
+                // a trap opens its frames in the category of the mode it enters
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.context.mode);
+            }
+            // reported right after the trap they belong to
+            Event::TrapCause | Event::TrapValue => {
+                if let Some(index) = self.last_trap {
+                    let key = if entry.event == Event::TrapCause { "cause" } else { "tval" };
+                    self.markers[index][5][key] = json!(format!("{:#x}", entry.arc.1));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn _flush(&mut self) {
+        // the trace may end without an End
+        if self.region.is_some() {
+            self.end_region(self.last_timestamp);
+        }
+        self.stack_unwinder.flush();
+        let profile = json!({
+            "meta": self.meta(),
+            "libs": [],
+            "threads": [self.thread()],
+            "processes": [],
+            "pausedRanges": [],
+        });
+        writeln!(self.writer, "{}", serde_json::to_string(&profile).unwrap()).unwrap();
+        self.writer.flush().unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use crate::backend::event::{Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::StackUnwinder;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+
+// a basic block as it was executed
+struct BlockRecord {
+    start: u64,
+    // address of the last instruction
+    end: u64,
+    // the event leaving the block and when, none for the block still running
+    exit: Option<(Event, u64)>,
+    // call-stack state while the block ran
+    depth: usize,
+    func: String,
+}
+
+pub struct FlightRecorderReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // the last blocks, oldest first
+    blocks: VecDeque<BlockRecord>,
+    max_blocks: usize,
+    // the block being executed
+    curr_block: Option<BlockRecord>,
+    // addresses that trigger a report when executed
+    triggers: HashSet<u64>,
+    last_timestamp: u64,
+    reports: usize,
+}
+
+impl FlightRecorderReceiver {
+    // triggers are symbol names or hex addresses
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, max_blocks: usize, triggers: Vec<String>) -> Result<Self> {
+        debug!("FlightRecorderReceiver::new");
+        let stack_unwinder = StackUnwinder::new(elf_path, debug_path)?;
+        let mut trigger_addrs = HashSet::new();
+        for trigger in triggers.iter() {
+            let addr = match trigger.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16)?,
+                None => stack_unwinder.func_symbol_map().values()
+                    .find(|symbol| symbol.name == *trigger || symbol.mangled_name == *trigger)
+                    .map(|symbol| symbol.address)
+                    .ok_or_else(|| anyhow!("unknown flight recorder trigger: {}", trigger))?,
+            };
+            trigger_addrs.insert(addr);
+        }
+        Ok(Self {
+            writer: BufWriter::new(File::create("trace.flight.txt").unwrap()),
+            receiver: BusReceiver {
+                name: "flight_recorder".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            blocks: VecDeque::with_capacity(max_blocks),
+            max_blocks,
+            curr_block: None,
+            triggers: trigger_addrs,
+            last_timestamp: 0,
+            reports: 0,
+        })
+    }
+
+    fn close_block(&mut self, event: Event, timestamp: u64) {
+        if let Some(mut block) = self.curr_block.take() {
+            block.exit = Some((event, timestamp));
+            if self.blocks.len() == self.max_blocks {
+                self.blocks.pop_front();
+            }
+            self.blocks.push_back(block);
+        }
+    }
+
+    fn write_block(&mut self, block: &BlockRecord) {
+        let start_loc = self.stack_unwinder.source_location(block.start);
+        let end_loc = self.stack_unwinder.source_location(block.end);
+        write!(self.writer, "  {:#x}-{:#x} {:<24} {}:{}-{} depth {} in {}", block.start, block.end, self.stack_unwinder.symbolize(block.start), start_loc.file, start_loc.lines, end_loc.lines, block.depth, block.func).unwrap();
+        match block.exit {
+            Some((event, timestamp)) => writeln!(self.writer, " -> {} [timestamp: {}]", event.to_string(), timestamp).unwrap(),
+            None => writeln!(self.writer, " (running)").unwrap(),
+        }
+    }
+
+    // dump the recorded blocks and the backtrace at pc
+    fn report(&mut self, reason: String, pc: u64, timestamp: u64) {
+        self.reports += 1;
+        writeln!(self.writer, "=== {} [timestamp: {}] ===", reason, timestamp).unwrap();
+        writeln!(self.writer, "last {} blocks, oldest first:", self.blocks.len() + self.curr_block.is_some() as usize).unwrap();
+        let blocks = std::mem::take(&mut self.blocks);
+        for block in blocks.iter() {
+            self.write_block(block);
+        }
+        self.blocks = blocks;
+        if let Some(block) = self.curr_block.take() {
+            self.write_block(&block);
+            self.curr_block = Some(block);
+        }
+        writeln!(self.writer, "backtrace:").unwrap();
+        // each frame where it is: pc, or the call site it will return to
+        for (depth, (frame, loc)) in self.stack_unwinder.backtrace(pc).iter().enumerate() {
+            let inferred = if frame.inferred { " [inferred]" } else { "" };
+            writeln!(self.writer, "  #{} {}{} at {}:{}", depth, frame.symbol.name, inferred, loc.file, loc.lines).unwrap();
+        }
+        writeln!(self.writer).unwrap();
+        // the decoder may not survive what comes next
+        self.writer.flush().unwrap();
+    }
+}
+
+impl AbstractReceiver for FlightRecorderReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        match entry.event {
+            Event::None => {
+                let addr = entry.arc.0;
+                if let Some(block) = self.curr_block.as_mut() {
+                    block.end = addr;
+                } else {
+                    let stack = self.stack_unwinder.stack();
+                    let func = stack.last().map_or(String::from("??"), |frame| frame.symbol.name.clone());
+                    self.curr_block = Some(BlockRecord { start: addr, end: addr, exit: None, depth: stack.len(), func });
+                }
+                if self.triggers.contains(&addr) {
+                    let reason = format!("reached {}", self.stack_unwinder.symbolize(addr));
+                    self.report(reason, addr, self.last_timestamp);
+                }
+            }
+            Event::Start => {
+                self.last_timestamp = entry.timestamp.unwrap();
+            }
+            Event::End => {
+                self.close_block(entry.event, entry.timestamp.unwrap());
+            }
+            // reported values do not end a block
+            Event::TrapCause | Event::TrapValue | Event::AsidChange | Event::ModeChange | Event::TaskSwitch => {}
+            _ => {
+                let timestamp = entry.timestamp.unwrap();
+                self.close_block(entry.event, timestamp);
+                self.last_timestamp = timestamp;
+                if entry.event == Event::TrapException {
+                    // the backtrace of the faulting code, before the handler is pushed
+                    let reason = format!("exception at {:#x} ({})", entry.arc.0, self.stack_unwinder.symbolize(entry.arc.0));
+                    self.report(reason, entry.arc.0, timestamp);
+                }
+            }
+        }
+        if StackUnwinder::steps_on(entry.event) {
+            self.stack_unwinder.step(&entry);
+        }
+    }
+
+    fn _flush(&mut self) {
+        println!("[flight_recorder] {} reports written", self.reports);
+        self.writer.flush().unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use crate::backend::event::{Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::StackUnwinder;
+use crate::backend::cost_accounting::TickSpreader;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use log::debug;
+
+// what a folded stack is weighted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FoldedWeight {
+    // timestamp ticks spent with the stack
+    Cycles,
+    // instructions executed with the stack
+    Insns,
+}
+
+// collapsed stacks (main;foo;bar <weight>) as read by flamegraph.pl, inferno and
+// difffolded.pl
+pub struct FoldedReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    weight: FoldedWeight,
+    // add the source line of each instruction as the leaf frame
+    lines: bool,
+    // the current stack, folded
+    curr_stack: String,
+    // folded stack -> weight, sorted for a stable output
+    stacks: BTreeMap<String, u64>,
+    // instructions per stack since the last timestamp, in order of execution
+    spreader: TickSpreader<String>,
+}
+
+impl FoldedReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, weight: FoldedWeight, lines: bool) -> Self {
+        debug!("FoldedReceiver::new");
+        Self {
+            writer: BufWriter::new(File::create("trace.folded").unwrap()),
+            receiver: BusReceiver {
+                name: "folded".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path, debug_path).unwrap(),
+            weight,
+            lines,
+            curr_stack: String::new(),
+            stacks: BTreeMap::new(),
+            spreader: TickSpreader::new(),
+        }
+    }
+
+    fn fold_stack(&self) -> String {
+        let names: Vec<String> = self.stack_unwinder.stack().iter().map(|frame| frame.symbol.name.replace(';', ":")).collect();
+        names.join(";")
+    }
+
+    // the current stack with the line of the instruction at addr as its leaf
+    fn stack_at(&self, addr: u64) -> String {
+        if !self.lines {
+            return self.curr_stack.clone();
+        }
+        let loc = self.stack_unwinder.source_location(addr);
+        let file = Path::new(&loc.file).file_name().map_or(String::from("??"), |name| name.to_string_lossy().to_string());
+        format!("{};{}:{}", self.curr_stack, file, loc.lines)
+    }
+
+    fn add(&mut self, stack: String, weight: u64) {
+        if weight > 0 && !stack.is_empty() {
+            *self.stacks.entry(stack).or_insert(0) += weight;
+        }
+    }
+
+    // spread the ticks since the last timestamp over the instructions executed
+    // meanwhile, or give them to the current stack if there were none
+    fn account(&mut self, timestamp: u64) {
+        let Some((ticks, shares)) = self.spreader.account(timestamp) else { return };
+        if shares.is_empty() {
+            let stack = self.curr_stack.clone();
+            self.add(stack, ticks);
+            return;
+        }
+        for (stack, share) in shares {
+            self.add(stack, share);
+        }
+    }
+}
+
+impl AbstractReceiver for FoldedReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        match entry.event {
+            Event::None => {
+                let stack = self.stack_at(entry.arc.0);
+                match self.weight {
+                    FoldedWeight::Insns => self.add(stack, 1),
+                    FoldedWeight::Cycles => self.spreader.push(stack),
+                }
+            }
+            Event::Start => {
+                self.spreader.start(entry.timestamp);
+                self.stack_unwinder.step(&entry);
+                self.curr_stack = self.fold_stack();
+            }
+            event if StackUnwinder::steps_on(event) => {
+                if self.weight == FoldedWeight::Cycles {
+                    self.account(entry.timestamp.unwrap());
+                }
+                if entry.event == Event::End {
+                    self.spreader.stop();
+                }
+                self.stack_unwinder.step(&entry);
+                self.curr_stack = self.fold_stack();
+            }
+            _ => {
+                // branches only move time forward, the stack stays
+                if let (Some(timestamp), FoldedWeight::Cycles) = (entry.timestamp, self.weight) {
+                    self.account(timestamp);
+                }
+            }
+        }
+    }
+
+    fn _flush(&mut self) {
+        // instructions after the last timestamp took no measurable time
+        self.spreader.clear();
+        for (stack, weight) in self.stacks.iter() {
+            writeln!(self.writer, "{} {}", stack, weight).unwrap();
+        }
+        self.writer.flush().unwrap();
+    }
+}
@@ -0,0 +1,198 @@
+use crate::backend::event::Entry;
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame, StackUpdate, INTERRUPT_FRAME_ADDR};
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use log::debug;
+
+// the decoder follows a single hart
+const HART: u64 = 0;
+
+// a call still open, and what it printed so far when calls are held back
+struct OpenCall {
+    frame: StackFrame,
+    depth: usize,
+    // its opening line is printed, which happens once something is printed under it
+    header_written: bool,
+    // its calls and their closing lines, kept until it is known to last long enough
+    lines: Vec<String>,
+}
+
+// indented calls and returns as printed by the ftrace function_graph tracer,
+// a leaf call on one line and the others on an opening and a closing line with
+// the duration in timestamp ticks, traps between arrows
+pub struct FunctionGraphReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // print the hart column
+    hart: bool,
+    // calls this deep and deeper are not printed, none to print all
+    max_depth: Option<usize>,
+    // calls shorter than this are not printed, 0 prints as the trace goes
+    min_cycles: u64,
+    calls: Vec<OpenCall>,
+    last_timestamp: u64,
+}
+
+impl FunctionGraphReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, hart: bool, max_depth: Option<usize>, min_cycles: u64) -> Self {
+        debug!("FunctionGraphReceiver::new");
+        let mut writer = BufWriter::new(File::create("trace.function_graph.txt").unwrap());
+        writeln!(writer, "# tracer: function_graph").unwrap();
+        writeln!(writer, "#").unwrap();
+        // the # stands in for the first column
+        let (hart_header, hart_bar) = if hart { ("HART ", "  |  ") } else { ("", "") };
+        writeln!(writer, "#{}{:>13} |  FUNCTION CALLS", hart_header, "DURATION").unwrap();
+        writeln!(writer, "#{}{:>13} |   |   |   |   |", hart_bar, "|").unwrap();
+        Self {
+            writer,
+            receiver: BusReceiver {
+                name: "function_graph".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path, debug_path).unwrap(),
+            hart,
+            max_depth,
+            min_cycles,
+            calls: Vec::new(),
+            last_timestamp: 0,
+        }
+    }
+
+    fn line(&self, duration: &str, depth: usize, text: &str) -> String {
+        let hart = if self.hart { format!("{:>3})  ", HART) } else { String::new() };
+        format!("{}{:>14} |  {}{}", hart, duration, "  ".repeat(depth), text)
+    }
+
+    fn shown(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    // print a line, or hold it back with the call it is under until that one closes
+    fn emit(&mut self, line: String) {
+        if self.min_cycles > 0 {
+            if let Some(parent) = self.calls.last_mut() {
+                parent.lines.push(line);
+                return;
+            }
+        }
+        writeln!(self.writer, "{}", line).unwrap();
+    }
+
+    fn opening_line(&self, call: &OpenCall) -> String {
+        let frame = &call.frame;
+        if frame.symbol.address >= INTERRUPT_FRAME_ADDR {
+            self.line("==========>", call.depth, &format!("/* {} */", frame.symbol.name))
+        } else if frame.inferred {
+            self.line("", call.depth, &format!("{}() {{ /* entered before the trace */", frame.symbol.name))
+        } else {
+            self.line("", call.depth, &format!("{}() {{", frame.symbol.name))
+        }
+    }
+
+    fn closing_line(&self, call: &OpenCall, duration: &str) -> String {
+        if call.frame.symbol.address >= INTERRUPT_FRAME_ADDR {
+            self.line("<==========", call.depth, &format!("/* {} {} */", call.frame.symbol.name, duration))
+        } else {
+            self.line(duration, call.depth, "}")
+        }
+    }
+
+    fn open(&mut self, frame: StackFrame) {
+        let depth = self.calls.len();
+        // the caller now has something printed under it, it is no leaf
+        if self.min_cycles == 0 && self.shown(depth) {
+            if let Some(parent) = self.calls.last() {
+                if !parent.header_written {
+                    let line = self.opening_line(parent);
+                    writeln!(self.writer, "{}", line).unwrap();
+                }
+            }
+            if let Some(parent) = self.calls.last_mut() {
+                parent.header_written = true;
+            }
+        }
+        self.calls.push(OpenCall { frame, depth, header_written: false, lines: Vec::new() });
+    }
+
+    fn close(&mut self, at: u64) {
+        let call = self.calls.pop().unwrap();
+        if !self.shown(call.depth) {
+            return;
+        }
+        let duration = call.frame.entered.map(|entered| at.saturating_sub(entered));
+        let duration_text = duration.map_or(String::new(), |duration| format!("{} cyc", duration));
+        let is_trap = call.frame.symbol.address >= INTERRUPT_FRAME_ADDR;
+        if self.min_cycles == 0 {
+            let line = if call.header_written || is_trap {
+                if is_trap && !call.header_written {
+                    let opening = self.opening_line(&call);
+                    writeln!(self.writer, "{}", opening).unwrap();
+                }
+                self.closing_line(&call, &duration_text)
+            } else {
+                self.line(&duration_text, call.depth, &format!("{}();", call.frame.symbol.name))
+            };
+            writeln!(self.writer, "{}", line).unwrap();
+            return;
+        }
+        // calls entered before the trace have no known duration and are kept
+        if duration.is_some_and(|duration| duration < self.min_cycles) {
+            return;
+        }
+        if call.lines.is_empty() && !is_trap {
+            let line = self.line(&duration_text, call.depth, &format!("{}();", call.frame.symbol.name));
+            self.emit(line);
+            return;
+        }
+        let opening = self.opening_line(&call);
+        let closing = self.closing_line(&call, &duration_text);
+        self.emit(opening);
+        for line in call.lines {
+            self.emit(line);
+        }
+        self.emit(closing);
+    }
+
+    fn apply(&mut self, update: StackUpdate, at: u64) {
+        for _ in update.closed.iter() {
+            self.close(at);
+        }
+        for frame in update.opened {
+            self.open(frame);
+        }
+    }
+}
+
+impl AbstractReceiver for FunctionGraphReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = timestamp;
+        }
+        if StackUnwinder::steps_on(entry.event) {
+            let update = self.stack_unwinder.step(&entry);
+            self.apply(update, entry.timestamp.unwrap());
+        }
+    }
+
+    fn _flush(&mut self) {
+        // calls still open end with the trace
+        let closed = self.stack_unwinder.flush();
+        self.apply(StackUpdate { closed, opened: Vec::new(), depth: 0 }, self.last_timestamp);
+        self.writer.flush().unwrap();
+    }
+}
@@ -1,5 +1,7 @@
   use crate::backend::event::{Entry, Event};
 use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{find_inline_frames, demangle_name};
+use crate::backend::debug_info::symbol_file;
 use bus::BusReader;
 use std::fs::File;
 use std::io::{BufWriter, Write};
@@ -20,18 +22,19 @@ pub struct GcdaReceiver {
 }
 
 impl GcdaReceiver {
-  pub fn new(bus_rx: BusReader<Entry>, gcno_path: String, elf_path: String) -> Self {
+  pub fn new(bus_rx: BusReader<Entry>, gcno_path: String, elf_path: String, debug_path: String) -> Self {
     // gcno handler
     let mut gcno_reader = GCNOReader::new(gcno_path.clone()).unwrap();
     let gcno = gcno_reader.parse().unwrap();
     let cfg = ControlFlowGraph::from(gcno);
     // addr2line handler
-    let loader = Loader::new(elf_path.clone()).unwrap();
+    let loader = Loader::new(debug_path.clone()).unwrap();
     // object handler
-    let elf_data = fs::read(elf_path.clone()).unwrap();
+    let elf_data = fs::read(symbol_file(&elf_path, &debug_path).unwrap()).unwrap();
     let obj_file = object::File::parse(&*elf_data).unwrap();
     let edge_map = cfg.report_instrumented_edges();
     let mut func_symbol_map = IndexMap::new();
+    // gcno records mangled names, so match on the raw ELF symbol names
     for symbol in obj_file.symbols() {
       if symbol.kind() == object::SymbolKind::Text {
         let func_name = symbol.name().unwrap();
@@ -52,6 +55,16 @@ impl GcdaReceiver {
           cfg: cfg,
     }
   }
+
+  // the location of an address plus the call sites of any functions inlined there,
+  // so that edges of both the inlined callee and its caller can be matched
+  fn source_locations(&self, addr: u64) -> Vec<SourceLocation> {
+    let mut locations = vec![SourceLocation::from_addr2line(self.loader.find_location(addr).unwrap())];
+    for frame in find_inline_frames(&self.loader, addr) {
+      locations.push(SourceLocation { file: frame.call_file, lines: frame.call_line });
+    }
+    locations
+  }
 }
 
 impl AbstractReceiver for GcdaReceiver {
@@ -67,12 +80,12 @@ impl AbstractReceiver for GcdaReceiver {
   fn _receive_entry(&mut self, entry: Entry) {
     match entry.event {
       Event::TakenBranch | Event::NonTakenBranch | Event::InferrableJump | Event::UninferableJump => {
-        let from_source: SourceLocation = SourceLocation::from_addr2line(self.loader.find_location(entry.arc.0).unwrap());
-        let to_source: SourceLocation = SourceLocation::from_addr2line(self.loader.find_location(entry.arc.1).unwrap());
+        let from_sources = self.source_locations(entry.arc.0);
+        let to_sources = self.source_locations(entry.arc.1);
         // match this to the edge map
         for (_, edges) in self.edge_map.iter_mut() {
           for edge in edges.iter_mut() {
-            if edge.from.contains(&from_source) && edge.to.contains(&to_source) {
+            if from_sources.iter().any(|s| edge.from.contains(s)) && to_sources.iter().any(|s| edge.to.contains(s)) {
               edge.increment_count();
               // a special debug for function "FloorPowerOfTwo"
               if edge.func_name == "FloorPowerOfTwo" {
@@ -98,7 +111,7 @@ impl AbstractReceiver for GcdaReceiver {
         if edge.entry == true {
           for (_, (iter_func_name, count)) in self.func_symbol_map.iter() {
             if iter_func_name == func_name {
-              trace!("merged entry edge for function: {:?}", func_name);
+              trace!("merged entry edge for function: {:?}", demangle_name(func_name));
               edge.count += *count;
             }
           }
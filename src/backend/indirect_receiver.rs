@@ -0,0 +1,216 @@
+use crate::backend::event::{Entry, Event, ControlFlowKind};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::StackUnwinder;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use log::debug;
+
+// one observed target of an indirect branch site
+struct TargetRecord {
+    // depends on the target too, e.g. a jr to a function entry is a tail call
+    cf_kind: ControlFlowKind,
+    count: u64,
+    first_seen: u64,
+}
+
+// an indirect call/jump instruction and everything it jumped to
+struct SiteRecord {
+    // target address -> record, sorted for a stable output
+    targets: BTreeMap<u64, TargetRecord>,
+}
+
+impl SiteRecord {
+    // every kind of jump the site made, in the order of its targets
+    fn kinds(&self) -> Vec<ControlFlowKind> {
+        let mut kinds = Vec::new();
+        for target in self.targets.values() {
+            if !kinds.contains(&target.cf_kind) {
+                kinds.push(target.cf_kind);
+            }
+        }
+        kinds
+    }
+}
+
+#[derive(Serialize)]
+struct TargetJson {
+    target: u64,
+    symbol: String,
+    kind: ControlFlowKind,
+    count: u64,
+    first_seen: u64,
+}
+
+#[derive(Serialize)]
+struct SiteJson {
+    site: u64,
+    symbol: String,
+    kinds: Vec<ControlFlowKind>,
+    count: u64,
+    targets: Vec<TargetJson>,
+}
+
+pub struct IndirectReceiver {
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    // site address -> record
+    sites: BTreeMap<u64, SiteRecord>,
+}
+
+impl IndirectReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String) -> Self {
+        debug!("IndirectReceiver::new");
+        Self::with_unwinder(bus_rx, StackUnwinder::new(elf_path, debug_path).unwrap())
+    }
+
+    fn with_unwinder(bus_rx: BusReader<Entry>, stack_unwinder: StackUnwinder) -> Self {
+        Self {
+            receiver: BusReceiver {
+                name: "indirect".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            sites: BTreeMap::new(),
+        }
+    }
+
+    fn write_json(&self, writer: &mut impl Write) {
+        let sites: Vec<SiteJson> = self.sites.iter().map(|(site, record)| SiteJson {
+            site: *site,
+            symbol: self.stack_unwinder.symbolize(*site),
+            kinds: record.kinds(),
+            count: record.targets.values().map(|target| target.count).sum(),
+            targets: record.targets.iter().map(|(target, target_record)| TargetJson {
+                target: *target,
+                symbol: self.stack_unwinder.symbolize(*target),
+                kind: target_record.cf_kind,
+                count: target_record.count,
+                first_seen: target_record.first_seen,
+            }).collect(),
+        }).collect();
+        writeln!(writer, "{}", serde_json::to_string_pretty(&sites).unwrap()).unwrap();
+    }
+
+    // one line per site listing every target it may jump to
+    fn write_policy(&self, writer: &mut impl Write) {
+        writeln!(writer, "# indirect branch allow-list: <site> <target>...").unwrap();
+        for (site, record) in self.sites.iter() {
+            let targets: Vec<String> = record.targets.keys().map(|target| format!("{:#x}", target)).collect();
+            let names: Vec<String> = record.targets.keys().map(|target| self.stack_unwinder.symbolize(*target)).collect();
+            let kinds: Vec<String> = record.kinds().iter().map(|cf_kind| cf_kind.to_string()).collect();
+            writeln!(writer, "{:#x} {} # {} ({}) -> {}", site, targets.join(" "), self.stack_unwinder.symbolize(*site), kinds.join("/"), names.join(", ")).unwrap();
+        }
+    }
+}
+
+impl AbstractReceiver for IndirectReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if entry.event != Event::UninferableJump {
+            return;
+        }
+        // returns are covered by the shadow stack, not by an allow-list
+        if !matches!(entry.cf_kind, ControlFlowKind::IndirectCall | ControlFlowKind::IndirectJump | ControlFlowKind::TailCall | ControlFlowKind::CoroutineSwap) {
+            return;
+        }
+        let site = self.sites.entry(entry.arc.0).or_insert_with(|| SiteRecord { targets: BTreeMap::new() });
+        let target = site.targets.entry(entry.arc.1).or_insert_with(|| TargetRecord { cf_kind: entry.cf_kind, count: 0, first_seen: entry.timestamp.unwrap() });
+        target.count += 1;
+    }
+
+    fn _flush(&mut self) {
+        let mut writer = BufWriter::new(File::create("trace.indirect.json").unwrap());
+        self.write_json(&mut writer);
+        writer.flush().unwrap();
+        let mut writer = BufWriter::new(File::create("trace.indirect.allow").unwrap());
+        self.write_policy(&mut writer);
+        writer.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stack_unwinder::SymbolInfo;
+
+    use indexmap::IndexMap;
+    use std::collections::HashMap;
+    use serde_json::{json, Value};
+
+    const MAIN: u64 = 0x1000;
+    const F: u64 = 0x1100;
+    const G: u64 = 0x1200;
+
+    fn receiver() -> IndirectReceiver {
+        let mut func_symbol_map = IndexMap::new();
+        for (index, (address, name)) in [(MAIN, "main"), (F, "f"), (G, "g")].into_iter().enumerate() {
+            func_symbol_map.insert(address, SymbolInfo { address, name: name.to_string(), mangled_name: name.to_string(), index: index as u32, line: 0, file: String::new() });
+        }
+        IndirectReceiver::with_unwinder(bus::Bus::new(1).add_rx(), StackUnwinder::from_maps(func_symbol_map, HashMap::new(), None))
+    }
+
+    fn jump(receiver: &mut IndirectReceiver, event: Event, cf_kind: ControlFlowKind, timestamp: u64, from: u64, to: u64) {
+        receiver._receive_entry(Entry::new_timed_jump(event, cf_kind, timestamp, from, to));
+    }
+
+    fn sample(receiver: &mut IndirectReceiver) {
+        // a function pointer called twice with f and once with g
+        jump(receiver, Event::UninferableJump, ControlFlowKind::IndirectCall, 1, MAIN + 0x10, F);
+        jump(receiver, Event::UninferableJump, ControlFlowKind::IndirectCall, 2, MAIN + 0x10, G);
+        jump(receiver, Event::UninferableJump, ControlFlowKind::IndirectCall, 3, MAIN + 0x10, F);
+        // a jump table, and the same jr as a tail call
+        jump(receiver, Event::UninferableJump, ControlFlowKind::IndirectJump, 4, F + 0x8, F + 0x40);
+        jump(receiver, Event::UninferableJump, ControlFlowKind::TailCall, 5, F + 0x8, G);
+        // not indirect, or covered by the shadow stack
+        jump(receiver, Event::InferrableJump, ControlFlowKind::Call, 6, MAIN + 0x20, G);
+        jump(receiver, Event::UninferableJump, ControlFlowKind::Return, 7, G + 0x8, MAIN + 0x24);
+    }
+
+    #[test]
+    fn test_json() {
+        let mut receiver = receiver();
+        sample(&mut receiver);
+        let mut json = Vec::new();
+        receiver.write_json(&mut json);
+        let json: Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json, json!([
+            {"site": MAIN + 0x10, "symbol": "main+0x10", "kinds": ["IndirectCall"], "count": 3, "targets": [
+                {"target": F, "symbol": "f", "kind": "IndirectCall", "count": 2, "first_seen": 1},
+                {"target": G, "symbol": "g", "kind": "IndirectCall", "count": 1, "first_seen": 2},
+            ]},
+            {"site": F + 0x8, "symbol": "f+0x8", "kinds": ["IndirectJump", "TailCall"], "count": 2, "targets": [
+                {"target": F + 0x40, "symbol": "f+0x40", "kind": "IndirectJump", "count": 1, "first_seen": 4},
+                {"target": G, "symbol": "g", "kind": "TailCall", "count": 1, "first_seen": 5},
+            ]},
+        ]));
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut receiver = receiver();
+        sample(&mut receiver);
+        let mut policy = Vec::new();
+        receiver.write_policy(&mut policy);
+        assert_eq!(String::from_utf8(policy).unwrap(), [
+            "# indirect branch allow-list: <site> <target>...",
+            "0x1010 0x1100 0x1200 # main+0x10 (IndirectCall) -> f, g",
+            "0x1108 0x1140 0x1200 # f+0x8 (IndirectJump/TailCall) -> f+0x40, g",
+            "",
+        ].join("\n"));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::backend::event::{Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackUpdate, INTERRUPT_FRAME_ADDR};
+use crate::backend::cost_accounting::{TickSpreader, CallSites};
+
+use bus::BusReader;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::collections::HashMap;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use object::{Object, ObjectSection};
+
+use log::debug;
+
+// the only mapping, the traced ELF
+const ELF_MAPPING_ID: u64 = 1;
+
+// protobuf wire types
+const WIRE_VARINT: u64 = 0;
+const WIRE_LEN: u64 = 2;
+
+// a protobuf message under construction, fields in order
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(buf: &mut Vec<u8>, value: u64) {
+        let mut value = value;
+        while value >= 0x80 {
+            buf.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        buf.push(value as u8);
+    }
+
+    // zero is the default and is left out
+    fn uint(&mut self, field: u64, value: u64) -> &mut Self {
+        if value != 0 {
+            Self::varint(&mut self.buf, field << 3 | WIRE_VARINT);
+            Self::varint(&mut self.buf, value);
+        }
+        self
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) -> &mut Self {
+        Self::varint(&mut self.buf, field << 3 | WIRE_LEN);
+        Self::varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    fn message(&mut self, field: u64, value: &Message) -> &mut Self {
+        self.bytes(field, &value.buf)
+    }
+
+    fn packed(&mut self, field: u64, values: &[u64]) -> &mut Self {
+        let mut packed = Vec::new();
+        for value in values {
+            Self::varint(&mut packed, *value);
+        }
+        self.bytes(field, &packed)
+    }
+}
+
+// a pprof Line, the function being one of the inline frames at a location
+struct Line {
+    function_id: u64,
+    line: u64,
+}
+
+struct Location {
+    address: u64,
+    // innermost inlined function first, the enclosing function last
+    lines: Vec<Line>,
+    // trap frames do not belong to the ELF
+    mapped: bool,
+}
+
+struct Function {
+    name: u64,
+    system_name: u64,
+    filename: u64,
+    start_line: u64,
+}
+
+// the profile.proto Profile, as read by pprof and Pyroscope-style viewers
+pub struct PprofReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    elf_path: String,
+    // address range of .text and the build id of the ELF
+    text_range: (u64, u64),
+    build_id: String,
+    // timestamp ticks per second, for the profile duration
+    clock_hz: u64,
+    // string table, "" first as required
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    // ids are indices + 1, 0 is not a valid id
+    locations: Vec<Location>,
+    location_ids: HashMap<u64, u64>,
+    functions: Vec<Function>,
+    function_ids: HashMap<(String, String), u64>,
+    // stacks as location ids, leaf first, and their instructions and cycles
+    samples: Vec<(Vec<u64>, [u64; 2])>,
+    sample_ids: HashMap<Vec<u64>, usize>,
+    call_sites: CallSites,
+    // location ids of the current stack except its leaf, innermost first
+    callers: Vec<u64>,
+    // samples of the instructions executed since the last timestamp, which share its ticks
+    spreader: TickSpreader<usize>,
+    last_sample: Option<usize>,
+    ticks: u64,
+}
+
+impl PprofReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, clock_hz: u64) -> Self {
+        debug!("PprofReceiver::new");
+        let elf_data = fs::read(&elf_path).unwrap();
+        let elf = object::File::parse(&*elf_data).unwrap();
+        let text_range = elf.section_by_name(".text").map_or((0, 0), |text| (text.address(), text.address() + text.size()));
+        let build_id = elf.build_id().ok().flatten().map_or(String::new(), |id| id.iter().map(|b| format!("{:02x}", b)).collect());
+        Self {
+            writer: BufWriter::new(File::create("trace.pb.gz").unwrap()),
+            receiver: BusReceiver {
+                name: "pprof".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path.clone(), debug_path).unwrap(),
+            elf_path,
+            text_range,
+            build_id,
+            clock_hz,
+            strings: vec![String::new()],
+            string_ids: HashMap::from([(String::new(), 0)]),
+            locations: Vec::new(),
+            location_ids: HashMap::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            samples: Vec::new(),
+            sample_ids: HashMap::new(),
+            call_sites: CallSites::new(),
+            callers: Vec::new(),
+            spreader: TickSpreader::new(),
+            last_sample: None,
+            ticks: 0,
+        }
+    }
+
+    fn string_id(&mut self, value: &str) -> u64 {
+        if let Some(id) = self.string_ids.get(value) {
+            return *id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(value.to_string());
+        self.string_ids.insert(value.to_string(), id);
+        id
+    }
+
+    fn function_id(&mut self, name: &str, mangled_name: &str, file: &str, start_line: u32) -> u64 {
+        let key = (mangled_name.to_string(), file.to_string());
+        if let Some(id) = self.function_ids.get(&key) {
+            return *id;
+        }
+        let function = Function {
+            name: self.string_id(name),
+            system_name: self.string_id(mangled_name),
+            filename: self.string_id(file),
+            start_line: start_line as u64,
+        };
+        self.functions.push(function);
+        let id = self.functions.len() as u64;
+        self.function_ids.insert(key, id);
+        id
+    }
+
+    // the location of an instruction, with a line for each function inlined at it
+    fn location_id(&mut self, addr: u64) -> u64 {
+        if let Some(id) = self.location_ids.get(&addr) {
+            return *id;
+        }
+        let location = if addr >= INTERRUPT_FRAME_ADDR {
+            let symbol = self.stack_unwinder.get_symbol_info(addr);
+            let function_id = self.function_id(&symbol.name, &symbol.mangled_name, "", 0);
+            Location { address: 0, lines: vec![Line { function_id, line: 0 }], mapped: false }
+        } else {
+            let inline_frames = self.stack_unwinder.inline_frames(addr).clone();
+            let mut lines = Vec::new();
+            // each inlined function is at the call site of the one inlined into it
+            let mut line = self.stack_unwinder.source_location(addr).lines;
+            for frame in inline_frames.iter().rev() {
+                let function_id = self.function_id(&frame.name, &frame.mangled_name, &frame.file, 0);
+                lines.push(Line { function_id, line: line as u64 });
+                line = frame.call_line;
+            }
+            if let Some(symbol) = self.stack_unwinder.symbol_containing(addr) {
+                let function_id = self.function_id(&symbol.name, &symbol.mangled_name, &symbol.file, symbol.line);
+                lines.push(Line { function_id, line: line as u64 });
+            }
+            Location { address: addr, lines, mapped: true }
+        };
+        self.locations.push(location);
+        let id = self.locations.len() as u64;
+        self.location_ids.insert(addr, id);
+        id
+    }
+
+    // keep the call sites in step with the unwinder, then rebuild the caller locations
+    fn apply(&mut self, update: &StackUpdate, site: u64) {
+        self.call_sites.apply(update, site, &self.stack_unwinder);
+        let stack = self.stack_unwinder.stack();
+        let mut callers = Vec::new();
+        // each frame is at the call into the frame above it, trap frames are where they are
+        for (depth, frame) in stack.iter().enumerate().rev().skip(1) {
+            let addr = if frame.symbol.address >= INTERRUPT_FRAME_ADDR { frame.symbol.address } else { self.call_sites.sites()[depth + 1] };
+            callers.push(self.location_id(addr));
+        }
+        // the leaf of a trap frame is the frame itself
+        if let Some(frame) = stack.last().filter(|frame| frame.symbol.address >= INTERRUPT_FRAME_ADDR) {
+            callers.insert(0, self.location_id(frame.symbol.address));
+        }
+        self.callers = callers;
+    }
+
+    fn sample_id(&mut self, addr: u64) -> usize {
+        let mut stack = vec![self.location_id(addr)];
+        stack.extend_from_slice(&self.callers);
+        if let Some(id) = self.sample_ids.get(&stack) {
+            return *id;
+        }
+        self.samples.push((stack.clone(), [0, 0]));
+        self.sample_ids.insert(stack, self.samples.len() - 1);
+        self.samples.len() - 1
+    }
+
+    // charge the ticks since the last timestamp to the instructions executed
+    // meanwhile, or to the last one if there were none
+    fn account(&mut self, timestamp: u64) {
+        let Some((ticks, shares)) = self.spreader.account(timestamp) else { return };
+        self.ticks += ticks;
+        if shares.is_empty() {
+            if let Some(sample) = self.last_sample {
+                self.samples[sample].1[1] += ticks;
+            }
+            return;
+        }
+        for (sample, share) in shares {
+            self.samples[sample].1[1] += share;
+        }
+    }
+
+    fn value_type(&mut self, type_: &str, unit: &str) -> Message {
+        let mut value_type = Message::default();
+        value_type.uint(1, self.string_id(type_)).uint(2, self.string_id(unit));
+        value_type
+    }
+
+    fn encode(&mut self) -> Message {
+        let mut profile = Message::default();
+        let instructions = self.value_type("instructions", "count");
+        let cycles = self.value_type("cycles", "count");
+        profile.message(1, &instructions).message(1, &cycles);
+        for (stack, values) in self.samples.iter() {
+            let mut sample = Message::default();
+            sample.packed(1, stack).packed(2, values);
+            profile.message(2, &sample);
+        }
+
+        let filename = self.string_id(&self.elf_path.clone());
+        let build_id = self.string_id(&self.build_id.clone());
+        let mut mapping = Message::default();
+        mapping.uint(1, ELF_MAPPING_ID).uint(2, self.text_range.0).uint(3, self.text_range.1)
+            .uint(5, filename).uint(6, build_id)
+            .uint(7, 1).uint(8, 1).uint(9, 1).uint(10, 1);
+        profile.message(3, &mapping);
+
+        for (index, location) in self.locations.iter().enumerate() {
+            let mut message = Message::default();
+            message.uint(1, index as u64 + 1).uint(2, if location.mapped { ELF_MAPPING_ID } else { 0 }).uint(3, location.address);
+            for line in location.lines.iter() {
+                let mut line_message = Message::default();
+                line_message.uint(1, line.function_id).uint(2, line.line);
+                message.message(4, &line_message);
+            }
+            profile.message(4, &message);
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            let mut message = Message::default();
+            message.uint(1, index as u64 + 1).uint(2, function.name).uint(3, function.system_name)
+                .uint(4, function.filename).uint(5, function.start_line);
+            profile.message(5, &message);
+        }
+
+        // period and defaults refer to the string table, fill it first
+        let duration_nanos = (self.ticks as u128 * 1_000_000_000 / self.clock_hz as u128) as u64;
+        let period_type = self.value_type("cycles", "count");
+        let default_sample_type = self.string_id("cycles");
+        for value in self.strings.iter() {
+            profile.bytes(6, value.as_bytes());
+        }
+        profile.uint(10, duration_nanos).message(11, &period_type).uint(12, 1).uint(14, default_sample_type);
+        profile
+    }
+}
+
+impl AbstractReceiver for PprofReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        match entry.event {
+            Event::None => {
+                let sample = self.sample_id(entry.arc.0);
+                self.samples[sample].1[0] += 1;
+                self.spreader.push(sample);
+                self.last_sample = Some(sample);
+            }
+            Event::Start => {
+                self.spreader.start(entry.timestamp);
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.arc.0);
+            }
+            event if StackUnwinder::steps_on(event) => {
+                self.account(entry.timestamp.unwrap());
+                // nothing is accounted between an end and the next start
+                if entry.event == Event::End {
+                    self.spreader.stop();
+                }
+                let update = self.stack_unwinder.step(&entry);
+                self.apply(&update, entry.arc.0);
+            }
+            _ => {
+                if let Some(timestamp) = entry.timestamp {
+                    self.account(timestamp);
+                }
+            }
+        }
+    }
+
+    fn _flush(&mut self) {
+        let profile = self.encode();
+        let mut encoder = GzEncoder::new(&mut self.writer, Compression::default());
+        encoder.write_all(&profile.buf).unwrap();
+        encoder.finish().unwrap();
+        self.writer.flush().unwrap();
+    }
+}
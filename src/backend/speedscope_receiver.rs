@@ -1,10 +1,11 @@
-use crate::backend::event::{Entry, Event};
+use crate::backend::event::{Entry, Event, SplitBy};
 use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
-use crate::backend::stack_unwinder::StackUnwinder;
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame};
 
 use bus::BusReader;
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::collections::HashMap;
 
 use jsonschema::{JSONSchema, Draft};
 use serde_json::{json, Value};
@@ -20,6 +21,16 @@ pub struct ProfileEntry {
     at: u64,
 }
 
+// the events of one region of interest, or of the whole trace,
+// and of one context (and task) if profiles are split
+struct RegionProfile {
+    region: u64,
+    context: Option<String>,
+    start: u64,
+    end: u64,
+    entries: Vec<ProfileEntry>,
+}
+
 pub struct SpeedscopeReceiver {
     writer: BufWriter<File>,
     receiver: BusReceiver,
@@ -29,15 +40,31 @@ pub struct SpeedscopeReceiver {
     end: u64,
     profile_entries: Vec<ProfileEntry>,
     stack_unwinder: StackUnwinder,
+    // (name, call file, call line) -> frame index of an inlined callee
+    inline_frame_indices: HashMap<(String, String, u32), u32>,
+    // open inline frames, one list per open symbol frame plus the base
+    inline_stacks: Vec<Vec<u32>>,
+    // instructions carry no timestamp, so use the last one seen
+    last_timestamp: u64,
+    // symbol index -> frame index of its inferred variant
+    inferred_frame_indices: HashMap<u32, u32>,
+    // the region being recorded and the ones already finished, one profile each
+    region: u64,
+    region_profiles: Vec<RegionProfile>,
+    // the context being recorded, and the profiles of the region so far, one per context
+    split_by: SplitBy,
+    context: Option<String>,
+    context_profiles: Vec<RegionProfile>,
 }
 
 impl SpeedscopeReceiver {
     
-    pub fn new(bus_rx: BusReader<Entry>, elf_path: String) -> Self {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, split_by: SplitBy, switch_symbols: Vec<String>) -> Self {
         debug!("SpeedscopeReceiver::new");
         
-        // create the stack unwinder
-        let stack_unwinder = StackUnwinder::new(elf_path.clone()).unwrap();
+        // create the stack unwinder, with a stack per task if context switches are known
+        let mut stack_unwinder = StackUnwinder::new(elf_path.clone(), debug_path).unwrap();
+        stack_unwinder.set_switch_symbols(&switch_symbols).unwrap();
 
         // Load the schema from the file
         let schema_file = File::open("src/backend/speedoscope-schema.json").unwrap();
@@ -66,8 +93,145 @@ impl SpeedscopeReceiver {
             end: 0,
             stack_unwinder,
             profile_entries: Vec::new(),
+            inline_frame_indices: HashMap::new(),
+            inline_stacks: vec![Vec::new()],
+            last_timestamp: 0,
+            inferred_frame_indices: HashMap::new(),
+            region: 0,
+            region_profiles: Vec::new(),
+            split_by,
+            context: None,
+            context_profiles: Vec::new(),
+        }
+    }
+
+    // inferred frames are shown apart from the ones entered during the trace
+    fn frame_index(&mut self, frame: &StackFrame) -> u32 {
+        if !frame.inferred {
+            return frame.symbol.index;
+        }
+        let frames = &mut self.frames;
+        *self.inferred_frame_indices.entry(frame.symbol.index).or_insert_with(|| {
+            frames.push(json!({"name": format!("{} [inferred]", frame.symbol.name), "line": frame.symbol.line, "file": frame.symbol.file}));
+            (frames.len() - 1) as u32
+        })
+    }
+
+    fn open_frame(&mut self, frame: &StackFrame, at: u64) {
+        let index = self.frame_index(frame);
+        if frame.inferred {
+            // the frame has been open since the start of the trace, below everything
+            // recorded so far, so patch it in before all other entries
+            self.switch_inline_stack(Vec::new(), at);
+            self.profile_entries.insert(0, ProfileEntry {
+                r#type: "O".to_string(),
+                frame: index,
+                at: self.start,
+            });
+        } else {
+            self.profile_entries.push(ProfileEntry {
+                r#type: "O".to_string(), // opening a frame
+                frame: index,
+                at,
+            });
+        }
+        self.inline_stacks.push(Vec::new());
+    }
+
+    // frame indices of the inline stack at an address, allocating frames on first sight
+    fn inline_stack_at(&mut self, addr: u64) -> Vec<u32> {
+        let mut stack = Vec::new();
+        for inline_frame in self.stack_unwinder.inline_frames(addr).iter() {
+            let key = (inline_frame.name.clone(), inline_frame.call_file.clone(), inline_frame.call_line);
+            let frames = &mut self.frames;
+            let index = *self.inline_frame_indices.entry(key).or_insert_with(|| {
+                frames.push(json!({"name": format!("{} [inlined]", inline_frame.name), "line": inline_frame.call_line, "file": inline_frame.call_file}));
+                (frames.len() - 1) as u32
+            });
+            stack.push(index);
+        }
+        stack
+    }
+
+    // move the inline frames of the current symbol frame to a new inline stack
+    fn switch_inline_stack(&mut self, new_stack: Vec<u32>, at: u64) {
+        let curr_stack = self.inline_stacks.last_mut().unwrap();
+        let common = curr_stack.iter().zip(new_stack.iter()).take_while(|(a, b)| a == b).count();
+        while curr_stack.len() > common {
+            let frame = curr_stack.pop().unwrap();
+            self.profile_entries.push(ProfileEntry { r#type: "C".to_string(), frame, at });
+        }
+        for frame in new_stack[common..].iter() {
+            curr_stack.push(*frame);
+            self.profile_entries.push(ProfileEntry { r#type: "O".to_string(), frame: *frame, at });
         }
     }
+
+    // close everything still open and add the events to the profile of the context
+    fn finish_context(&mut self, closed_frames: Vec<StackFrame>) {
+        for frame in closed_frames.iter() {
+            self.close_frame(frame, self.end);
+        }
+        // and the inline frames of the outermost function
+        self.switch_inline_stack(Vec::new(), self.end);
+        let entries = std::mem::take(&mut self.profile_entries);
+        // a context passed through without taking any time, e.g. right before a reported mode
+        if self.context.is_some() && self.start == self.end {
+            return;
+        }
+        match self.context_profiles.iter_mut().find(|profile| profile.context == self.context) {
+            Some(profile) => {
+                profile.end = self.end;
+                profile.entries.extend(entries);
+            }
+            None => self.context_profiles.push(RegionProfile {
+                region: self.region,
+                context: self.context.clone(),
+                start: self.start,
+                end: self.end,
+                entries,
+            }),
+        }
+    }
+
+    // close everything still open and set the region's events aside
+    fn finish_region(&mut self, closed_frames: Vec<StackFrame>) {
+        self.finish_context(closed_frames);
+        self.region_profiles.append(&mut self.context_profiles);
+    }
+
+    // the profile an entry goes to, by its context and the running task
+    fn profile_key(&self, entry: &Entry) -> Option<String> {
+        let task = self.stack_unwinder.tracks_tasks().then(|| format!("task-{}", self.stack_unwinder.task()));
+        match (entry.context.key(self.split_by), task) {
+            (Some(context), Some(task)) => Some(format!("{}.{}", context, task)),
+            (context, task) => context.or(task),
+        }
+    }
+
+    // the current stack leaves the profile of the old context and enters the new one's
+    fn switch_context(&mut self, context: Option<String>, at: u64) {
+        let stack = self.stack_unwinder.stack();
+        self.end = at;
+        self.finish_context(stack.iter().rev().cloned().collect());
+        self.context = context;
+        self.start = at;
+        for frame in stack.iter() {
+            self.open_frame(frame, at);
+        }
+    }
+
+    // close a symbol frame together with the inline frames opened inside it
+    fn close_frame(&mut self, frame: &StackFrame, at: u64) {
+        self.switch_inline_stack(Vec::new(), at);
+        self.inline_stacks.pop();
+        let index = self.frame_index(frame);
+        self.profile_entries.push(ProfileEntry {
+            r#type: "C".to_string(), // closing a frame
+            frame: index,
+            at,
+        });
+    }
 }
 
 impl AbstractReceiver for SpeedscopeReceiver {
@@ -81,52 +245,74 @@ impl AbstractReceiver for SpeedscopeReceiver {
     }
 
     fn _receive_entry(&mut self, entry: Entry) {
+        let context = self.profile_key(&entry);
+        // the handler is profiled in the context it runs in, so switch before a trap
+        // and after its return
+        let switch = context != self.context && !matches!(entry.event, Event::Start | Event::End);
+        if switch && entry.event != Event::TrapReturn {
+            self.switch_context(context.clone(), entry.timestamp.unwrap_or(self.last_timestamp));
+        }
         match entry.event {
-            Event::InferrableJump => {
-                let (success, frame_stack_size, opened_frame) = self.stack_unwinder.step_ij(entry.clone());
-                if success {
-                    self.profile_entries.push(ProfileEntry {
-                        r#type: "O".to_string(), // opening a frame
-                        frame: opened_frame.unwrap().index,
-                        at: entry.timestamp.unwrap(),
-                    });
+            // traps open an [interrupt] or [exception] frame below the handler
+            event if !matches!(event, Event::Start | Event::End) && StackUnwinder::steps_on(event) => {
+                let task = self.stack_unwinder.task();
+                let update = self.stack_unwinder.step(&entry);
+                for frame in update.closed.iter() {
+                    self.close_frame(frame, entry.timestamp.unwrap());
                 }
-            }
-            Event::UninferableJump => {
-                let (success, frame_stack_size, closed_frames) = self.stack_unwinder.step_uj(entry.clone());
-                if success {
-                    for frame in closed_frames {
-                        self.profile_entries.push(ProfileEntry {
-                            r#type: "C".to_string(), // closing a frame
-                            frame: frame.index,
-                            at: entry.timestamp.unwrap(),
-                        });
-                    }
+                if self.stack_unwinder.task() != task {
+                    // the old task's frames are all closed, the new task gets its own profile
+                    self.end = entry.timestamp.unwrap();
+                    self.finish_context(Vec::new());
+                    self.context = self.profile_key(&entry);
+                    self.start = entry.timestamp.unwrap();
                 }
+                for frame in update.opened.iter() {
+                    self.open_frame(frame, entry.timestamp.unwrap());
+                }
+                self.last_timestamp = entry.timestamp.unwrap();
+            }
+            Event::None => {
+                let new_stack = self.inline_stack_at(entry.arc.0);
+                self.switch_inline_stack(new_stack, self.last_timestamp);
             }
             Event::Start => {
                 // debug!("start: {}", entry.timestamp.unwrap());
                 self.start = entry.timestamp.unwrap();
+                self.last_timestamp = entry.timestamp.unwrap();
+                // regions of interest are numbered in arc.1
+                self.region = entry.arc.1;
+                self.context = context.clone();
+                // the function the trace starts in
+                for frame in self.stack_unwinder.step(&entry).opened.iter() {
+                    self.open_frame(frame, self.start);
+                }
             }
             Event::End => {
                 // debug!("end: {}", entry.timestamp.unwrap());
                 self.end = entry.timestamp.unwrap();
+                let closed_frames = self.stack_unwinder.step(&entry).closed;
+                self.finish_region(closed_frames);
             }
             _ => {
-                // do nothing
+                if let Some(timestamp) = entry.timestamp {
+                    self.last_timestamp = timestamp;
+                }
+            }
+        }
+        if entry.event == Event::TrapReturn {
+            let context = self.profile_key(&entry);
+            if context != self.context {
+                self.switch_context(context, self.last_timestamp);
             }
         }
     }
 
     fn _flush(&mut self) {
-        // forcefully close all open frames
-        let closed_frames = self.stack_unwinder.flush();
-        for frame in closed_frames {
-            self.profile_entries.push(ProfileEntry {
-                r#type: "C".to_string(), // closing a frame
-                frame: frame.index,
-                at: self.end,
-            });
+        // forcefully close all open frames, if the trace ended without an End
+        if !self.profile_entries.is_empty() || !self.context_profiles.is_empty() {
+            let closed_frames = self.stack_unwinder.flush();
+            self.finish_region(closed_frames);
         }
         
         // Write the JSON structure manually in a deterministic order
@@ -140,8 +326,9 @@ impl AbstractReceiver for SpeedscopeReceiver {
         for (i, frame) in self.frames.iter().enumerate() {
             let comma = if i < self.frames.len() - 1 { "," } else { "" };
             writeln!(self.writer, "      {{").unwrap();
-            writeln!(self.writer, "        \"name\": \"{}\",", frame["name"].as_str().unwrap()).unwrap();
-            writeln!(self.writer, "        \"file\": \"{}\",", frame["file"].as_str().unwrap()).unwrap();
+            // demangled names may contain characters that need escaping
+            writeln!(self.writer, "        \"name\": {},", frame["name"]).unwrap();
+            writeln!(self.writer, "        \"file\": {},", frame["file"]).unwrap();
             writeln!(self.writer, "        \"line\": {}", frame["line"].as_u64().unwrap()).unwrap();
             writeln!(self.writer, "      }}{}", comma).unwrap();
         }
@@ -149,26 +336,48 @@ impl AbstractReceiver for SpeedscopeReceiver {
         writeln!(self.writer, "    ]").unwrap();
         writeln!(self.writer, "  }},").unwrap();
         writeln!(self.writer, "  \"profiles\": [").unwrap();
-        writeln!(self.writer, "    {{").unwrap();
-        writeln!(self.writer, "      \"name\": \"tacit\",").unwrap();
-        writeln!(self.writer, "      \"type\": \"evented\",").unwrap();
-        writeln!(self.writer, "      \"unit\": \"none\",").unwrap();
-        writeln!(self.writer, "      \"startValue\": {},", self.start).unwrap();
-        writeln!(self.writer, "      \"endValue\": {},", self.end).unwrap();
-        writeln!(self.writer, "      \"events\": [").unwrap();
-        
-        // Write profile entries in order
-        for (i, entry) in self.profile_entries.iter().enumerate() {
-            let comma = if i < self.profile_entries.len() - 1 { "," } else { "" };
-            writeln!(self.writer, "        {{").unwrap();
-            writeln!(self.writer, "          \"type\": \"{}\",", entry.r#type).unwrap();
-            writeln!(self.writer, "          \"frame\": {},", entry.frame).unwrap();
-            writeln!(self.writer, "          \"at\": {}", entry.at).unwrap();
-            writeln!(self.writer, "        }}{}", comma).unwrap();
+
+        // one profile per region of interest and context, a filter may split a region into parts
+        for (p, profile) in self.region_profiles.iter().enumerate() {
+            let same_part = |other: &&RegionProfile| other.region == profile.region && other.context == profile.context;
+            let parts = self.region_profiles.iter().filter(same_part).count();
+            let part = self.region_profiles[..p].iter().filter(same_part).count();
+            let mut tags = Vec::new();
+            if parts > 1 {
+                tags.push(format!("region {}, part {}", profile.region, part));
+            } else if self.region_profiles.iter().any(|other| other.region != profile.region) {
+                tags.push(format!("region {}", profile.region));
+            }
+            if let Some(context) = profile.context.as_ref() {
+                tags.push(context.clone());
+            }
+            let name = if tags.is_empty() {
+                String::from("tacit")
+            } else {
+                format!("tacit ({})", tags.join(", "))
+            };
+            writeln!(self.writer, "    {{").unwrap();
+            writeln!(self.writer, "      \"name\": \"{}\",", name).unwrap();
+            writeln!(self.writer, "      \"type\": \"evented\",").unwrap();
+            writeln!(self.writer, "      \"unit\": \"none\",").unwrap();
+            writeln!(self.writer, "      \"startValue\": {},", profile.start).unwrap();
+            writeln!(self.writer, "      \"endValue\": {},", profile.end).unwrap();
+            writeln!(self.writer, "      \"events\": [").unwrap();
+
+            // Write profile entries in order
+            for (i, entry) in profile.entries.iter().enumerate() {
+                let comma = if i < profile.entries.len() - 1 { "," } else { "" };
+                writeln!(self.writer, "        {{").unwrap();
+                writeln!(self.writer, "          \"type\": \"{}\",", entry.r#type).unwrap();
+                writeln!(self.writer, "          \"frame\": {},", entry.frame).unwrap();
+                writeln!(self.writer, "          \"at\": {}", entry.at).unwrap();
+                writeln!(self.writer, "        }}{}", comma).unwrap();
+            }
+
+            let comma = if p < self.region_profiles.len() - 1 { "," } else { "" };
+            writeln!(self.writer, "      ]").unwrap();
+            writeln!(self.writer, "    }}{}", comma).unwrap();
         }
-        
-        writeln!(self.writer, "      ]").unwrap();
-        writeln!(self.writer, "    }}").unwrap();
         writeln!(self.writer, "  ]").unwrap();
         writeln!(self.writer, "}}").unwrap();
         
@@ -1,5 +1,5 @@
 use indexmap::IndexMap;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 // objdump dependency
 use capstone::prelude::*;
@@ -12,29 +12,44 @@ use std::io::Read;
 use gcno_reader::cfg::SourceLocation;
 
 use std::fs;
+use std::borrow::Cow;
 use addr2line::Loader;
 
 use log::{trace, debug, warn};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
-use crate::backend::event::{Entry, Event};
+use crate::backend::event::{Entry, Event, ControlFlowKind};
+use crate::backend::debug_info::symbol_file;
 
 // everything you need to know about a symbol
 #[derive(Clone)]
 pub struct SymbolInfo {
-    pub name: String,
+    pub address: u64,
+    pub name: String, // demangled, for display
+    pub mangled_name: String, // as in the ELF, for matching against gcno/afdo
     pub index: u32, 
     pub line: u32,
     pub file: String,
 }
 
+// one level of inlining at an address
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct InlineFrame {
+    pub name: String,
+    pub mangled_name: String,
+    // location inside the inlined function
+    pub file: String,
+    pub line: u32,
+    // where the inlined function was expanded into its caller
+    pub call_file: String,
+    pub call_line: u32,
+}
+
 #[derive(Clone)]
 pub struct InsnInfo {
     pub address: u64,
     pub len: usize,
     pub bytes: Vec<u8>,
-    pub mnemonic: String,
-    pub op_str: String,
 }
 
 impl<'a> From<&Insn<'a>> for InsnInfo {
@@ -43,12 +58,58 @@ impl<'a> From<&Insn<'a>> for InsnInfo {
             address: insn.address(),
             len: insn.len(),
             bytes: insn.bytes().to_vec(),
-            mnemonic: insn.mnemonic().unwrap().to_string(),
-            op_str: insn.op_str().unwrap().to_string(),
         }
     }
 }
 
+// synthetic symbols for trap frames, placed where no code can be
+pub const INTERRUPT_FRAME_ADDR: u64 = u64::MAX - 1;
+pub const EXCEPTION_FRAME_ADDR: u64 = u64::MAX;
+
+// an open frame on the shadow stack
+#[derive(Clone)]
+struct Frame {
+    index: u32,
+    // where the matching return lands, 0 if unknown
+    return_addr: u64,
+    inferred: bool,
+    // an [interrupt] or [exception] frame, only a trap return closes it
+    trap: bool,
+    // when the frame was opened
+    entered: u64,
+}
+
+// a frame as reported to receivers
+#[derive(Clone)]
+pub struct StackFrame {
+    pub symbol: SymbolInfo,
+    // never entered during the trace, reconstructed from the trace start or a
+    // return past the outermost frame. it is open since the start of the trace
+    pub inferred: bool,
+    // when the frame was entered, none for inferred frames
+    pub entered: Option<u64>,
+}
+
+// frames closed and opened by a single jump or trap
+// closed frames come innermost first and are closed before the opened ones
+// opened frames come outermost first, a trap opens its trap frame and the handler
+// an inferred opened frame goes below all others, from the start of the trace
+pub struct StackUpdate {
+    pub closed: Vec<StackFrame>,
+    pub opened: Vec<StackFrame>,
+    pub depth: usize, // frame stack size after the update
+}
+
+// the stack of a task that is not running
+struct ParkedTask {
+    task: u64,
+    // where the task continues when switched back to, 0 if it is only known by id
+    resume_addr: u64,
+    // switched out by a trap handler, which may skip the trapping instruction
+    trapped: bool,
+    frames: Vec<Frame>,
+}
+
 pub struct StackUnwinder {
     // addr -> symbol info <name, index, line, file>
     func_symbol_map: IndexMap<u64, SymbolInfo>,
@@ -56,12 +117,29 @@ pub struct StackUnwinder {
     idx_2_addr_range: IndexMap<u32, (u64, u64)>,
     // addr -> insn
     insn_map: HashMap<u64, InsnInfo>,
+    // function start addresses, sorted
+    func_addr_sorted: Vec<u64>,
     // stack model
-    frame_stack: Vec<u32>, // Queue of index
+    frame_stack: Vec<Frame>,
+    // debug info handler, used for inline expansion
+    loader: Option<Loader>,
+    // addr -> inline stack
+    inline_cache: HashMap<u64, Vec<InlineFrame>>,
+    // indices of the context-switch functions
+    switch_funcs: HashSet<u32>,
+    // the running task and the stacks of the others, oldest first
+    task: u64,
+    next_task: u64,
+    parked_tasks: Vec<ParkedTask>,
+    // a switch function ran inside a trap handler, the switch happens at its return
+    switch_pending: bool,
+    // once the trace names tasks, switch functions are not needed to tell them apart
+    task_ids_reported: bool,
 }
 
 impl StackUnwinder {
-    pub fn new(elf_path: String) -> Result<Self> {
+    // debug_path is the file carrying DWARF, see debug_info::find_debug_file
+    pub fn new(elf_path: String, debug_path: String) -> Result<Self> {
         // create insn_map
         let mut elf_file = File::open(elf_path.clone())?;
         let mut elf_buffer = Vec::new();
@@ -92,21 +170,23 @@ impl StackUnwinder {
 
         // create func_symbol_map
         let mut func_symbol_map: IndexMap<u64, SymbolInfo> = IndexMap::new();
-        // object handler
-        let elf_data = fs::read(elf_path.clone()).unwrap();
+        // object handler, stripped binaries take their symbols from the debug file
+        let elf_data = fs::read(symbol_file(&elf_path, &debug_path)?).unwrap();
         let obj_file = object::File::parse(&*elf_data).unwrap();
-        let loader = Loader::new(elf_path.clone()).unwrap();
+        let loader = Loader::new(debug_path.clone()).unwrap();
         let mut next_index = 0;
         for symbol in obj_file.symbols().filter(|s| s.kind() == object::SymbolKind::Text) {
             let func_addr = symbol.address();
             let loc: SourceLocation = SourceLocation::from_addr2line(loader.find_location(func_addr).unwrap());
             let func_info = SymbolInfo {
-                name: String::from(symbol.name().unwrap()),
+                address: func_addr,
+                name: demangle_name(symbol.name().unwrap()),
+                mangled_name: String::from(symbol.name().unwrap()),
                 index: next_index,
                 line: loc.lines,
                 file: String::from(loc.file),
             };
-            trace!("func_info: addr: {:#x}, name: {}, index: {}", func_addr, func_info.name, func_info.index);
+            trace!("func_info: addr: {:#x}, name: {} ({}), index: {}", func_addr, func_info.name, func_info.mangled_name, func_info.index);
             // check if the func_addr is already in the map
             if func_symbol_map.contains_key(&func_addr) {
                 warn!("func_addr: {:#x} already in the map with name: {}", func_addr, func_symbol_map[&func_addr].name);
@@ -117,6 +197,14 @@ impl StackUnwinder {
             }
         }
 
+        Ok(Self::from_maps(func_symbol_map, insn_map, Some(loader)))
+    }
+
+    // the unwinder over already collected functions and instructions, without
+    // a loader there are no source locations or inline frames
+    pub fn from_maps(mut func_symbol_map: IndexMap<u64, SymbolInfo>, insn_map: HashMap<u64, InsnInfo>, loader: Option<Loader>) -> Self {
+        let mut next_index = func_symbol_map.len() as u32;
+
         // sort the func_symbol_map by address
         let mut func_symbol_addr_sorted = func_symbol_map.keys().cloned().collect::<Vec<u64>>();
         func_symbol_addr_sorted.sort();
@@ -130,80 +218,713 @@ impl StackUnwinder {
             idx_2_addr_range.insert(func_info.index, (addr.clone(), next_addr.clone()));
         }
 
-        Ok(Self {
+        // trap frames, kept out of func_symbol_addr_sorted so no address resolves to them
+        for (trap_addr, trap_name) in [(INTERRUPT_FRAME_ADDR, "[interrupt]"), (EXCEPTION_FRAME_ADDR, "[exception]")] {
+            func_symbol_map.insert(trap_addr, SymbolInfo {
+                address: trap_addr,
+                name: trap_name.to_string(),
+                mangled_name: trap_name.to_string(),
+                index: next_index,
+                line: 0,
+                file: String::new(),
+            });
+            idx_2_addr_range.insert(next_index, (trap_addr, trap_addr));
+            next_index += 1;
+        }
+
+        Self {
             func_symbol_map: func_symbol_map,
             idx_2_addr_range: idx_2_addr_range,
             insn_map: insn_map,
+            func_addr_sorted: func_symbol_addr_sorted,
             frame_stack: Vec::new(),
-        })
+            loader,
+            inline_cache: HashMap::new(),
+            switch_funcs: HashSet::new(),
+            task: 0,
+            next_task: 1,
+            parked_tasks: Vec::new(),
+            switch_pending: false,
+            task_ids_reported: false,
+        }
+    }
+
+    // functions (by name or 0x address) after which another task runs, e.g.
+    // __switch_to, which returns into the next task, or vTaskSwitchContext,
+    // whose handler returns into it
+    pub fn set_switch_symbols(&mut self, symbols: &[String]) -> Result<()> {
+        for symbol in symbols.iter() {
+            let addr = match symbol.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16)?,
+                None => self.func_symbol_map.values()
+                    .find(|symbol_info| symbol_info.name == *symbol || symbol_info.mangled_name == *symbol)
+                    .map(|symbol_info| symbol_info.address)
+                    .ok_or_else(|| anyhow!("unknown context-switch symbol: {}", symbol))?,
+            };
+            let symbol_info = self.func_symbol_map.get(&addr).ok_or_else(|| anyhow!("no function starts at {:#x}", addr))?;
+            self.switch_funcs.insert(symbol_info.index);
+        }
+        Ok(())
+    }
+
+    // whether stacks are kept per task
+    pub fn tracks_tasks(&self) -> bool {
+        !self.switch_funcs.is_empty() || self.task_ids_reported
+    }
+
+    // the running task, numbered in order of appearance unless the trace names them
+    pub fn task(&self) -> u64 {
+        self.task
     }
 
     pub fn func_symbol_map(&self) -> &IndexMap<u64, SymbolInfo> {
         &self.func_symbol_map
     }
     
-    // return (success, frame_stack_size, symbol_info)
-    pub fn step_ij(&mut self, entry: Entry) -> (bool, usize, Option<SymbolInfo>) {
-        assert!(entry.event == Event::InferrableJump);
-        if self.func_symbol_map.contains_key(&entry.arc.1) {
-            let frame_idx = self.func_symbol_map[&entry.arc.1].index;
-            self.frame_stack.push(frame_idx);
-            return (true, self.frame_stack.len(), Some(self.func_symbol_map[&entry.arc.1].clone()));
-        } else {
-            return (false, self.frame_stack.len(), None);
+    // whether step takes this event: everything that opens, closes or swaps frames
+    // receivers step on exactly these, so that their stacks agree
+    pub fn steps_on(event: Event) -> bool {
+        matches!(event, Event::Start | Event::End | Event::InferrableJump | Event::UninferableJump
+            | Event::TrapException | Event::TrapInterrupt | Event::TrapReturn | Event::TaskSwitch)
+    }
+
+    // whether step brings in the stack of another task on this entry
+    pub fn switches_task(&self, entry: &Entry) -> bool {
+        match entry.event {
+            Event::TaskSwitch => true,
+            Event::TrapReturn => self.switch_pending && self.trap_depth() > 0,
+            Event::InferrableJump | Event::UninferableJump => {
+                entry.cf_kind == ControlFlowKind::Return && self.returns_from_switch() && self.trap_depth() == 0
+            }
+            _ => false,
         }
     }
 
-    pub fn step_uj(&mut self, entry: Entry) -> (bool, usize, Vec<SymbolInfo>) {
-        assert!(entry.event == Event::UninferableJump);
-        // get the previous instruction - is it a ret or c.jr ra?
-        let prev_insn = self.insn_map.get(&entry.arc.0).unwrap();
-        let target_frame_addr = entry.arc.1;
-        let mut closed_frames = Vec::new();
-        // if we come in with an empty stack, we did not close any frames
-        if self.frame_stack.is_empty() {
-            return (false, self.frame_stack.len(), closed_frames);
-        }
-        // if we come in with an em
-        if prev_insn.mnemonic == "ret" || (prev_insn.mnemonic == "c.jr" && prev_insn.op_str == "ra") {
-            loop {
-                // peek the top of the stack
-                if let Some(frame_idx) = self.frame_stack.last() {
-                    // if this function range is within the target frame range, we can stop
-                    let (start, end) = self.idx_2_addr_range[frame_idx];
-                    if target_frame_addr >= start && target_frame_addr < end {
-                        return (true, self.frame_stack.len(), closed_frames);
-                    }
-                    // if not, pop the stack
-                    if let Some(frame_idx) = self.frame_stack.pop() {
-                        trace!("closing frame: {}", frame_idx);
-                        let func_start_addr = self.idx_2_addr_range[&frame_idx].0;
-                        closed_frames.push(self.func_symbol_map[&func_start_addr].clone());
-                    } // if the stack is empty, we are done
-                    else {
-                        return (true, self.frame_stack.len(), closed_frames);
+    // update the shadow stack with a jump or trap and report the frames it closed and opened
+    // also takes the start of the trace (or of a region of interest), which opens a frame
+    // for the function it starts in, and its end, which closes everything
+    pub fn step(&mut self, entry: &Entry) -> StackUpdate {
+        let target = entry.arc.1;
+        let timestamp = entry.timestamp.unwrap_or(0);
+        let mut closed = Vec::new();
+        let mut opened = Vec::new();
+        match entry.event {
+            Event::Start => {
+                closed = self.pop_frames(0);
+                opened.extend(self.push_frame(entry.arc.0, 0, true, timestamp));
+                return StackUpdate { closed, opened, depth: self.frame_stack.len() };
+            }
+            Event::End => {
+                closed = self.pop_frames(0);
+                return StackUpdate { closed, opened, depth: 0 };
+            }
+            Event::TrapException | Event::TrapInterrupt => {
+                // the handler runs on top of whatever was interrupted
+                let trap_addr = if entry.event == Event::TrapInterrupt { INTERRUPT_FRAME_ADDR } else { EXCEPTION_FRAME_ADDR };
+                let symbol_info = self.func_symbol_map[&trap_addr].clone();
+                trace!("opening trap frame: {} at {:#x}", symbol_info.name, entry.arc.0);
+                self.frame_stack.push(Frame { index: symbol_info.index, return_addr: entry.arc.0, inferred: false, trap: true, entered: timestamp });
+                opened.push(StackFrame { symbol: symbol_info, inferred: false, entered: Some(timestamp) });
+                opened.extend(self.push_frame(target, 0, false, timestamp));
+                return StackUpdate { closed, opened, depth: self.frame_stack.len() };
+            }
+            Event::TaskSwitch => {
+                // the task named in arc.1 runs from here on, at the pc in arc.0
+                self.task_ids_reported = true;
+                self.switch_pending = false;
+                return self.switch_task(Some(target), 0, false, entry.arc.0, timestamp);
+            }
+            Event::TrapReturn => {
+                // back to exactly the stack the innermost trap interrupted
+                if let Some(depth) = self.frame_stack.iter().rposition(|frame| frame.trap) {
+                    let trap_pc = self.frame_stack[depth].return_addr;
+                    closed = self.pop_frames(depth);
+                    if self.switch_pending {
+                        // the handler returns into another task than the one it interrupted
+                        self.switch_pending = false;
+                        let mut update = self.switch_task(None, trap_pc, true, target, timestamp);
+                        closed.append(&mut update.closed);
+                        update.closed = closed;
+                        return update;
                     }
-                // could have dropped to a frame outside the target range
+                    return StackUpdate { closed, opened, depth: self.frame_stack.len() };
+                }
+                // the trap was taken before the trace started, so this is just a return
+                closed = self.unwind_to(target);
+                if self.frame_stack.is_empty() {
+                    opened.extend(self.push_frame(target, 0, true, timestamp));
+                }
+                return StackUpdate { closed, opened, depth: self.frame_stack.len() };
+            }
+            _ => assert!(entry.event == Event::InferrableJump || entry.event == Event::UninferableJump),
+        }
+        // the address a call made by this jump would return to
+        let return_addr = entry.arc.0 + self.insn_map.get(&entry.arc.0).map_or(0, |insn| insn.len as u64);
+        match entry.cf_kind {
+            ControlFlowKind::Call | ControlFlowKind::IndirectCall => {
+                opened.extend(self.push_frame(target, return_addr, false, timestamp));
+            }
+            ControlFlowKind::TailCall => {
+                // the callee returns to where the replaced frame would have
+                let return_addr = self.replaceable_frame().map_or(0, |frame| frame.return_addr);
+                closed = self.pop_frames(self.frame_stack.len().saturating_sub(1).max(self.trap_depth()));
+                opened.extend(self.push_frame(target, return_addr, false, timestamp));
+            }
+            ControlFlowKind::CoroutineSwap => {
+                closed = self.pop_frames(self.frame_stack.len().saturating_sub(1).max(self.trap_depth()));
+                opened.extend(self.push_frame(target, return_addr, false, timestamp));
+            }
+            ControlFlowKind::Return if self.returns_from_switch() => {
+                if self.trap_depth() > 0 {
+                    // the switch takes effect when the handler returns
+                    self.switch_pending = true;
+                    closed = self.unwind_to(target);
                 } else {
-                    return (true, self.frame_stack.len(), closed_frames);
+                    // returns into another task, which continues where it last left the switch function
+                    let resume_addr = self.frame_stack.last().unwrap().return_addr;
+                    return self.switch_task(None, resume_addr, false, target, timestamp);
                 }
-            } 
-        } else {
-            // not a return
-            return (false, self.frame_stack.len(), closed_frames);
+            }
+            ControlFlowKind::Return => {
+                closed = self.unwind_to(target);
+                // returned past the outermost frame, so the function we landed in
+                // has been the caller of everything seen so far
+                if self.frame_stack.is_empty() {
+                    opened.extend(self.push_frame(target, 0, true, timestamp));
+                }
+            }
+            ControlFlowKind::IndirectJump => {
+                // leaving the current function without a call, e.g. longjmp
+                if !self.frame_stack.last().is_some_and(|frame| !frame.trap && self.frame_contains(frame.index, target)) {
+                    let trap_depth = self.trap_depth();
+                    if self.frame_stack[trap_depth..].iter().any(|frame| frame.return_addr == target || self.frame_contains(frame.index, target)) {
+                        closed = self.unwind_to(target);
+                    } else if let Some(frame) = self.replaceable_frame() {
+                        // jumped into the middle of another function, treat it like a tail call
+                        let return_addr = frame.return_addr;
+                        closed = self.pop_frames(self.frame_stack.len() - 1);
+                        opened.extend(self.push_frame(target, return_addr, false, timestamp));
+                    }
+                }
+            }
+            ControlFlowKind::Jump | ControlFlowKind::None => {}
+        }
+        StackUpdate { closed, opened, depth: self.frame_stack.len() }
+    }
+
+    // the innermost frame is a context-switch function, and tasks are only known by it
+    fn returns_from_switch(&self) -> bool {
+        !self.task_ids_reported && self.replaceable_frame().is_some_and(|frame| self.switch_funcs.contains(&frame.index))
+    }
+
+    // park the running task, resuming at resume_addr, and bring in the stack of
+    // the next task: the one named, or the one that resumes at pc
+    // everything of the old task is closed and everything of the new one opened
+    fn switch_task(&mut self, task: Option<u64>, resume_addr: u64, trapped: bool, pc: u64, timestamp: u64) -> StackUpdate {
+        let frames = std::mem::take(&mut self.frame_stack);
+        let closed = frames.iter().rev().map(|frame| self.stack_frame_of(frame)).collect();
+        self.parked_tasks.push(ParkedTask { task: self.task, resume_addr, trapped, frames });
+        let position = match task {
+            Some(task) => self.parked_tasks.iter().position(|parked| parked.task == task),
+            None => self.parked_tasks.iter().position(|parked| self.resumes_at(parked, pc)),
+        };
+        let prev_task = self.task;
+        match position {
+            Some(position) => {
+                let parked = self.parked_tasks.remove(position);
+                self.task = parked.task;
+                self.frame_stack = parked.frames;
+                // left through the switch function, which has now returned
+                if task.is_none() {
+                    self.unwind_to(pc);
+                }
+            }
+            None => {
+                // a task seen for the first time
+                self.task = task.unwrap_or_else(|| {
+                    self.next_task += 1;
+                    self.next_task - 1
+                });
+            }
         }
+        debug!("switching from task {} to task {} at {:#x}", prev_task, self.task, pc);
+        let mut opened: Vec<StackFrame> = self.stack();
+        if self.frame_stack.is_empty() {
+            opened.extend(self.push_frame(pc, 0, true, timestamp));
+        }
+        StackUpdate { closed, opened, depth: self.frame_stack.len() }
+    }
+
+    // a parked task continues at its resume address, or right after it when
+    // a handler skips the trapping instruction (e.g. an ecall yielding)
+    fn resumes_at(&self, parked: &ParkedTask, pc: u64) -> bool {
+        let resume_addr = parked.resume_addr;
+        resume_addr != 0 && (pc == resume_addr || parked.trapped && self.insn_map.get(&resume_addr).is_some_and(|insn| pc == resume_addr + insn.len as u64))
+    }
+
+    // frames below this depth belong to code interrupted by a trap and are
+    // left alone until the trap returns
+    fn trap_depth(&self) -> usize {
+        self.frame_stack.iter().rposition(|frame| frame.trap).map_or(0, |i| i + 1)
+    }
+
+    // the innermost frame, unless it is a trap frame
+    fn replaceable_frame(&self) -> Option<&Frame> {
+        self.frame_stack.last().filter(|frame| !frame.trap)
     }
 
-    pub fn flush(&mut self) -> Vec<SymbolInfo> {
+    // open a frame for the function containing addr, if it is a known function
+    fn push_frame(&mut self, addr: u64, return_addr: u64, inferred: bool, timestamp: u64) -> Option<StackFrame> {
+        let func_addr = self.func_containing(addr)?;
+        let symbol_info = self.func_symbol_map[&func_addr].clone();
+        trace!("opening frame: {} returning to {:#x}, inferred: {}", symbol_info.index, return_addr, inferred);
+        self.frame_stack.push(Frame { index: symbol_info.index, return_addr, inferred, trap: false, entered: timestamp });
+        Some(StackFrame { symbol: symbol_info, inferred, entered: (!inferred).then_some(timestamp) })
+    }
+
+    // close frames until depth frames are left, innermost first
+    fn pop_frames(&mut self, depth: usize) -> Vec<StackFrame> {
         let mut closed_frames = Vec::new();
-        while let Some(frame_idx) = self.frame_stack.pop() {
-            trace!("closing frame while flushing: {}", frame_idx);
-            closed_frames.push(self.func_symbol_map[&self.idx_2_addr_range[&frame_idx].0].clone());
+        while self.frame_stack.len() > depth {
+            let frame = self.frame_stack.pop().unwrap();
+            trace!("closing frame: {}", frame.index);
+            closed_frames.push(self.stack_frame_of(&frame));
         }
         closed_frames
     }
 
+    // close the frames a return (or non-local jump) to target leaves
+    // never unwinds past a trap frame, only a trap return does that
+    fn unwind_to(&mut self, target: u64) -> Vec<StackFrame> {
+        let trap_depth = self.trap_depth();
+        let frames = &self.frame_stack[trap_depth..];
+        // the innermost frame expecting to return to target, also skips frames
+        // that were left without returning (e.g. longjmp over them)
+        if let Some(depth) = frames.iter().rposition(|frame| frame.return_addr == target) {
+            return self.pop_frames(trap_depth + depth);
+        }
+        // no frame matches, pop until we are back in the function containing target
+        let depth = frames.iter().rposition(|frame| self.frame_contains(frame.index, target)).map_or(0, |i| i + 1);
+        self.pop_frames(trap_depth + depth)
+    }
+
+    // whether the function with this index contains addr
+    fn frame_contains(&self, index: u32, addr: u64) -> bool {
+        let (start, end) = self.idx_2_addr_range[&index];
+        // the last function has no known end
+        addr >= start && (addr < end || end <= start)
+    }
+
+    // start address of the function containing addr
+    pub fn func_containing(&self, addr: u64) -> Option<u64> {
+        let position = self.func_addr_sorted.partition_point(|&start| start <= addr);
+        if position == 0 {
+            None
+        } else {
+            Some(self.func_addr_sorted[position - 1])
+        }
+    }
+
+    fn stack_frame_of(&self, frame: &Frame) -> StackFrame {
+        StackFrame { symbol: self.symbol_info_of(frame.index), inferred: frame.inferred, entered: (!frame.inferred).then_some(frame.entered) }
+    }
+
+    fn symbol_info_of(&self, index: u32) -> SymbolInfo {
+        self.func_symbol_map[&self.idx_2_addr_range[&index].0].clone()
+    }
+
+    pub fn flush(&mut self) -> Vec<StackFrame> {
+        trace!("closing {} frames while flushing", self.frame_stack.len());
+        self.pop_frames(0)
+    }
+
+    // number of inferred frames, they are always at the bottom of the stack
+    pub fn inferred_depth(&self) -> usize {
+        self.frame_stack.iter().take_while(|frame| frame.inferred).count()
+    }
+
     pub fn get_symbol_info(&self, addr: u64) -> SymbolInfo {
         self.func_symbol_map[&addr].clone()
     }
+
+    // the function containing addr, if it is a known function
+    pub fn symbol_containing(&self, addr: u64) -> Option<SymbolInfo> {
+        self.func_containing(addr).map(|func_addr| self.func_symbol_map[&func_addr].clone())
+    }
+
+    // function+offset, for reports read without a disassembly at hand
+    pub fn symbolize(&self, addr: u64) -> String {
+        match self.symbol_containing(addr) {
+            Some(symbol) if symbol.address == addr => symbol.name,
+            Some(symbol) => format!("{}+{:#x}", symbol.name, addr - symbol.address),
+            None => String::from("??"),
+        }
+    }
+
+    // start addresses of the functions of the open frames, outermost first
+    // cheaper than stack() when only the functions matter
+    pub fn frame_addrs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.frame_stack.iter().map(|frame| self.idx_2_addr_range[&frame.index].0)
+    }
+
+    // open frames, outermost first
+    pub fn stack(&self) -> Vec<StackFrame> {
+        self.frame_stack.iter().map(|frame| self.stack_frame_of(frame)).collect()
+    }
+
+    // open frames innermost first, each with where it currently is: pc for the
+    // innermost frame, the call site (or trapping pc) for the others
+    pub fn backtrace(&self, pc: u64) -> Vec<(StackFrame, SourceLocation)> {
+        self.backtrace_of(&self.frame_stack, Some(pc))
+    }
+
+    // backtraces of the tasks not running, by task, in the order they were parked
+    // where inside the innermost frame each of them stopped is not known
+    pub fn parked_backtraces(&self) -> Vec<(u64, Vec<(StackFrame, SourceLocation)>)> {
+        self.parked_tasks.iter().map(|parked| (parked.task, self.backtrace_of(&parked.frames, None))).collect()
+    }
+
+    fn backtrace_of(&self, frames: &[Frame], pc: Option<u64>) -> Vec<(StackFrame, SourceLocation)> {
+        let mut backtrace = Vec::new();
+        let mut curr_pc = pc;
+        for frame in frames.iter().rev() {
+            let loc = if frame.trap {
+                SourceLocation { file: String::new(), lines: 0 }
+            } else if let Some(curr_pc) = curr_pc {
+                self.source_location(curr_pc)
+            } else {
+                // where the call came from is unknown, fall back to the function itself
+                let symbol_info = self.symbol_info_of(frame.index);
+                SourceLocation { file: symbol_info.file, lines: symbol_info.line }
+            };
+            backtrace.push((self.stack_frame_of(frame), loc));
+            // return addresses point past the call, step back into it
+            curr_pc = match frame.return_addr {
+                0 => None,
+                return_addr if frame.trap => Some(return_addr),
+                return_addr => Some(return_addr - 1),
+            };
+        }
+        backtrace
+    }
+
+    // the decoded instruction starting at addr, none if addr is not an instruction boundary
+    pub fn insn_at(&self, addr: u64) -> Option<&InsnInfo> {
+        self.insn_map.get(&addr)
+    }
+
+    pub fn source_location(&self, addr: u64) -> SourceLocation {
+        SourceLocation::from_addr2line(self.loader.as_ref().and_then(|loader| loader.find_location(addr).ok().flatten()))
+    }
+
+    // inline stack at an address, outermost inlined callee first
+    pub fn inline_frames(&mut self, addr: u64) -> &Vec<InlineFrame> {
+        let loader = &self.loader;
+        self.inline_cache.entry(addr).or_insert_with(|| loader.as_ref().map_or(Vec::new(), |loader| find_inline_frames(loader, addr)))
+    }
+}
+
+// expand an address into the functions inlined at it, outermost first
+// the enclosing (non-inlined) function itself is not part of the result
+pub fn find_inline_frames(loader: &Loader, addr: u64) -> Vec<InlineFrame> {
+    // addr2line reports frames innermost first, each with its location in that function
+    let mut frames: Vec<(String, String, SourceLocation)> = Vec::new();
+    if let Ok(mut frame_iter) = loader.find_frames(addr) {
+        while let Ok(Some(frame)) = frame_iter.next() {
+            let mangled_name = frame.function.as_ref()
+                .and_then(|f| f.raw_name().ok())
+                .map(|n| n.to_string())
+                .unwrap_or_default();
+            let name = demangle_name(&mangled_name);
+            frames.push((name, mangled_name, SourceLocation::from_addr2line(frame.location)));
+        }
+    }
+    // the location of the next outer frame is the call site of the inner one
+    let mut inline_frames: Vec<InlineFrame> = frames.windows(2).map(|pair| {
+        let (name, mangled_name, loc) = &pair[0];
+        let (_, _, call_site) = &pair[1];
+        InlineFrame {
+            name: name.clone(),
+            mangled_name: mangled_name.clone(),
+            file: loc.file.clone(),
+            line: loc.lines,
+            call_file: call_site.file.clone(),
+            call_line: call_site.lines,
+        }
+    }).collect();
+    inline_frames.reverse();
+    inline_frames
+}
+
+// demangle an Itanium (C++) or Rust (legacy and v0) symbol name
+// names that are not mangled are returned unchanged
+pub fn demangle_name(name: &str) -> String {
+    // plain C names such as "f" would otherwise demangle as builtin types
+    if !name.starts_with("_Z") && !name.starts_with("_R") {
+        return name.to_string();
+    }
+    addr2line::demangle_auto(Cow::from(name), None).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: u64 = 0x1000;
+    const F: u64 = 0x1100;
+    const G: u64 = 0x1200;
+    const H: u64 = 0x1300;
+    const HANDLER: u64 = 0x1400;
+    const END: u64 = 0x1500;
+
+    // functions of 0x100 bytes, only the jumps the tests take are decoded
+    fn unwinder(jumps: &[(u64, usize)]) -> StackUnwinder {
+        let mut func_symbol_map = IndexMap::new();
+        for (index, (address, name)) in [(MAIN, "main"), (F, "f"), (G, "g"), (H, "h"), (HANDLER, "handler"), (END, "end")].into_iter().enumerate() {
+            func_symbol_map.insert(address, SymbolInfo { address, name: name.to_string(), mangled_name: name.to_string(), index: index as u32, line: 0, file: String::new() });
+        }
+        let insn_map = jumps.iter().map(|&(address, len)| {
+            (address, InsnInfo { address, len, bytes: vec![0; len] })
+        }).collect();
+        StackUnwinder::from_maps(func_symbol_map, insn_map, None)
+    }
+
+    fn jump(unwinder: &mut StackUnwinder, cf_kind: ControlFlowKind, timestamp: u64, from: u64, to: u64) -> StackUpdate {
+        unwinder.step(&Entry::new_timed_jump(Event::UninferableJump, cf_kind, timestamp, from, to))
+    }
+
+    fn event(unwinder: &mut StackUnwinder, event: Event, timestamp: u64, from: u64, to: u64) -> StackUpdate {
+        unwinder.step(&Entry::new_timed_event(event, timestamp, from, to))
+    }
+
+    fn names(frames: &[StackFrame]) -> Vec<String> {
+        frames.iter().map(|frame| frame.symbol.name.clone()).collect()
+    }
+
+    fn stack_names(unwinder: &StackUnwinder) -> Vec<String> {
+        names(&unwinder.stack())
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (F + 0x20, 2)]);
+        let update = event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        assert_eq!(names(&update.opened), ["main"]);
+        // jal
+        let update = jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        assert_eq!(names(&update.opened), ["f"]);
+        assert_eq!(update.opened[0].entered, Some(1));
+        assert_eq!(update.depth, 2);
+        // c.jalr, returns past the compressed call
+        let update = jump(&mut unwinder, ControlFlowKind::IndirectCall, 2, F + 0x20, G);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert_eq!(stack_names(&unwinder), ["main", "f", "g"]);
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 3, G + 0x8, F + 0x22);
+        assert_eq!(names(&update.closed), ["g"]);
+        assert!(update.opened.is_empty());
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 4, F + 0x30, MAIN + 0x14);
+        assert_eq!(names(&update.closed), ["f"]);
+        assert_eq!(update.depth, 1);
+        let update = event(&mut unwinder, Event::End, 5, MAIN + 0x20, 0);
+        assert_eq!(names(&update.closed), ["main"]);
+        assert_eq!(update.depth, 0);
+    }
+
+    #[test]
+    fn test_jumps_inside_a_function() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4)]);
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        let update = jump(&mut unwinder, ControlFlowKind::Jump, 2, F + 0x8, F + 0x40);
+        assert!(update.closed.is_empty() && update.opened.is_empty());
+        // a jump table
+        let update = jump(&mut unwinder, ControlFlowKind::IndirectJump, 3, F + 0x40, F + 0x80);
+        assert!(update.closed.is_empty() && update.opened.is_empty());
+        assert_eq!(stack_names(&unwinder), ["main", "f"]);
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4)]);
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        // j g
+        let update = jump(&mut unwinder, ControlFlowKind::TailCall, 2, F + 0x8, G);
+        assert_eq!(names(&update.closed), ["f"]);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert_eq!(stack_names(&unwinder), ["main", "g"]);
+        // jr a5 into the middle of another function
+        let update = jump(&mut unwinder, ControlFlowKind::IndirectJump, 3, G + 0x8, H + 0x10);
+        assert_eq!(names(&update.closed), ["g"]);
+        assert_eq!(names(&update.opened), ["h"]);
+        // h returns to where f was called
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 4, H + 0x20, MAIN + 0x14);
+        assert_eq!(names(&update.closed), ["h"]);
+        assert_eq!(stack_names(&unwinder), ["main"]);
+    }
+
+    #[test]
+    fn test_returns_skipping_frames() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (F + 0x10, 4), (G + 0x10, 4)]);
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        jump(&mut unwinder, ControlFlowKind::Call, 2, F + 0x10, G);
+        jump(&mut unwinder, ControlFlowKind::Call, 3, G + 0x10, H);
+        // longjmp returning from setjmp in main, over g and f
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 4, H + 0x20, MAIN + 0x14);
+        assert_eq!(names(&update.closed), ["h", "g", "f"]);
+        assert_eq!(update.depth, 1);
+        jump(&mut unwinder, ControlFlowKind::Call, 5, MAIN + 0x10, F);
+        jump(&mut unwinder, ControlFlowKind::Call, 6, F + 0x10, G);
+        // longjmp through jr to an address that no frame returns to
+        let update = jump(&mut unwinder, ControlFlowKind::IndirectJump, 7, G + 0x20, MAIN + 0x40);
+        assert_eq!(names(&update.closed), ["g", "f"]);
+        assert_eq!(stack_names(&unwinder), ["main"]);
+    }
+
+    #[test]
+    fn test_trap_and_trap_return() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (HANDLER + 0x10, 4)]);
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        let update = event(&mut unwinder, Event::TrapException, 2, F + 0x8, HANDLER);
+        assert_eq!(names(&update.opened), ["[exception]", "handler"]);
+        assert_eq!(update.depth, 4);
+        // an interrupt nested in the handler
+        let update = event(&mut unwinder, Event::TrapInterrupt, 3, HANDLER + 0x20, HANDLER);
+        assert_eq!(names(&update.opened), ["[interrupt]", "handler"]);
+        let update = event(&mut unwinder, Event::TrapReturn, 4, HANDLER + 0x30, HANDLER + 0x20);
+        assert_eq!(names(&update.closed), ["handler", "[interrupt]"]);
+        assert_eq!(stack_names(&unwinder), ["main", "f", "[exception]", "handler"]);
+        // a return nowhere in the handler stops at the trap frame
+        jump(&mut unwinder, ControlFlowKind::Call, 5, HANDLER + 0x10, G);
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 6, G + 0x8, MAIN + 0x14);
+        assert_eq!(names(&update.closed), ["g", "handler"]);
+        assert_eq!(stack_names(&unwinder), ["main", "f", "[exception]"]);
+        // back to exactly the interrupted stack, wherever mret goes
+        let update = event(&mut unwinder, Event::TrapReturn, 7, G + 0x30, F + 0xc);
+        assert_eq!(names(&update.closed), ["[exception]"]);
+        assert!(update.opened.is_empty());
+        assert_eq!(stack_names(&unwinder), ["main", "f"]);
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 8, F + 0x20, MAIN + 0x14);
+        assert_eq!(names(&update.closed), ["f"]);
+    }
+
+    #[test]
+    fn test_inferred_callers() {
+        let mut unwinder = unwinder(&[(F + 0x10, 4)]);
+        // the trace starts in the middle of g
+        let update = event(&mut unwinder, Event::Start, 0, G + 0x10, 0);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert!(update.opened[0].inferred);
+        assert_eq!(update.opened[0].entered, None);
+        // returning past the outermost frame reveals its caller
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 1, G + 0x20, F + 0x14);
+        assert_eq!(names(&update.closed), ["g"]);
+        assert_eq!(names(&update.opened), ["f"]);
+        assert!(update.opened[0].inferred);
+        assert_eq!(unwinder.inferred_depth(), 1);
+        // frames entered during the trace are not inferred
+        let update = jump(&mut unwinder, ControlFlowKind::Call, 2, F + 0x10, H);
+        assert!(!update.opened[0].inferred);
+        assert_eq!(unwinder.inferred_depth(), 1);
+        jump(&mut unwinder, ControlFlowKind::Return, 3, H + 0x8, F + 0x14);
+        // a trap return for a trap taken before the trace started
+        let update = event(&mut unwinder, Event::TrapReturn, 4, F + 0x20, MAIN + 0x40);
+        assert_eq!(names(&update.closed), ["f"]);
+        assert_eq!(names(&update.opened), ["main"]);
+        assert!(update.opened[0].inferred);
+        assert_eq!(stack_names(&unwinder), ["main"]);
+    }
+
+    #[test]
+    fn test_switch_symbol() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (F + 0x10, 4), (G + 0x10, 4)]);
+        unwinder.set_switch_symbols(&["h".to_string()]).unwrap();
+        assert!(unwinder.tracks_tasks());
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        jump(&mut unwinder, ControlFlowKind::Call, 2, F + 0x10, H);
+        // h returns into a task not seen before, the first one is parked
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 3, H + 0x8, G + 0x30);
+        assert_eq!(names(&update.closed), ["h", "f", "main"]);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert!(update.opened[0].inferred);
+        assert_eq!(unwinder.task(), 1);
+        let parked: Vec<(u64, Vec<String>)> = unwinder.parked_backtraces().into_iter()
+            .map(|(task, frames)| (task, frames.into_iter().map(|(frame, _)| frame.symbol.name).collect()))
+            .collect();
+        assert_eq!(parked, [(0, vec!["h".to_string(), "f".to_string(), "main".to_string()])]);
+        // and resumed where it called h
+        jump(&mut unwinder, ControlFlowKind::Call, 4, G + 0x10, H);
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 5, H + 0x8, F + 0x14);
+        assert_eq!(names(&update.closed), ["h", "g"]);
+        assert_eq!(names(&update.opened), ["main", "f"]);
+        assert_eq!(update.depth, 2);
+        assert_eq!(unwinder.task(), 0);
+        // a return from another function is not a switch
+        jump(&mut unwinder, ControlFlowKind::Call, 6, F + 0x10, G);
+        jump(&mut unwinder, ControlFlowKind::Return, 7, G + 0x8, F + 0x14);
+        assert_eq!(unwinder.task(), 0);
+        assert_eq!(stack_names(&unwinder), ["main", "f"]);
+    }
+
+    #[test]
+    fn test_switch_in_trap_handler() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (F + 0x8, 4), (G + 0x50, 4), (HANDLER + 0x10, 4)]);
+        unwinder.set_switch_symbols(&["h".to_string()]).unwrap();
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        event(&mut unwinder, Event::TrapInterrupt, 2, F + 0x8, HANDLER);
+        jump(&mut unwinder, ControlFlowKind::Call, 3, HANDLER + 0x10, H);
+        // the switch waits for the handler to return
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 4, H + 0x8, HANDLER + 0x14);
+        assert_eq!(names(&update.closed), ["h"]);
+        assert_eq!(unwinder.task(), 0);
+        let update = event(&mut unwinder, Event::TrapReturn, 5, HANDLER + 0x20, G + 0x40);
+        assert_eq!(names(&update.closed), ["handler", "[interrupt]", "f", "main"]);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert_eq!(unwinder.task(), 1);
+        // an ecall yields, its handler returns past the ecall of the parked task
+        event(&mut unwinder, Event::TrapException, 6, G + 0x50, HANDLER);
+        jump(&mut unwinder, ControlFlowKind::Call, 7, HANDLER + 0x10, H);
+        jump(&mut unwinder, ControlFlowKind::Return, 8, H + 0x8, HANDLER + 0x14);
+        let update = event(&mut unwinder, Event::TrapReturn, 9, HANDLER + 0x20, F + 0x8);
+        assert_eq!(names(&update.closed), ["handler", "[exception]", "g"]);
+        assert_eq!(names(&update.opened), ["main", "f"]);
+        assert_eq!(unwinder.task(), 0);
+        // task 1 continues after its ecall
+        event(&mut unwinder, Event::TrapInterrupt, 10, F + 0x20, HANDLER);
+        jump(&mut unwinder, ControlFlowKind::Call, 11, HANDLER + 0x10, H);
+        jump(&mut unwinder, ControlFlowKind::Return, 12, H + 0x8, HANDLER + 0x14);
+        let update = event(&mut unwinder, Event::TrapReturn, 13, HANDLER + 0x20, G + 0x54);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert_eq!(unwinder.task(), 1);
+    }
+
+    #[test]
+    fn test_reported_task_ids() {
+        let mut unwinder = unwinder(&[(MAIN + 0x10, 4), (G + 0x10, 4)]);
+        unwinder.set_switch_symbols(&["h".to_string()]).unwrap();
+        event(&mut unwinder, Event::Start, 0, MAIN, 0);
+        jump(&mut unwinder, ControlFlowKind::Call, 1, MAIN + 0x10, F);
+        let update = event(&mut unwinder, Event::TaskSwitch, 2, G + 0x8, 7);
+        assert_eq!(names(&update.closed), ["f", "main"]);
+        assert_eq!(names(&update.opened), ["g"]);
+        assert_eq!(unwinder.task(), 7);
+        // named tasks make the switch symbols plain functions
+        jump(&mut unwinder, ControlFlowKind::Call, 3, G + 0x10, H);
+        let update = jump(&mut unwinder, ControlFlowKind::Return, 4, H + 0x8, G + 0x14);
+        assert_eq!(names(&update.closed), ["h"]);
+        assert_eq!(unwinder.task(), 7);
+        jump(&mut unwinder, ControlFlowKind::Call, 5, G + 0x10, H);
+        let update = event(&mut unwinder, Event::TaskSwitch, 6, F + 0x20, 0);
+        assert_eq!(names(&update.closed), ["h", "g"]);
+        assert_eq!(names(&update.opened), ["main", "f"]);
+        assert_eq!(unwinder.task(), 0);
+        let update = event(&mut unwinder, Event::TaskSwitch, 7, H + 0x10, 7);
+        assert_eq!(names(&update.opened), ["g", "h"]);
+        assert!(!update.opened[1].inferred);
+        assert_eq!(update.opened[1].entered, Some(5));
+    }
 }
@@ -1,5 +1,6 @@
-use crate::backend::event::{Entry, Event};
+use crate::backend::event::{Entry, Event, ControlFlowKind};
 use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, InlineFrame};
 use bus::BusReader;
 use std::fs::File;
 use std::io::{BufWriter, Write};
@@ -7,12 +8,36 @@ use std::io::{BufWriter, Write};
 pub struct TxtReceiver {
     writer: BufWriter<File>,
     receiver: BusReceiver,
+    // only present if inline annotations are requested
+    stack_unwinder: Option<StackUnwinder>,
+    curr_inline: Vec<InlineFrame>,
 }
 
 impl TxtReceiver {
-    pub fn new(bus_rx: BusReader<Entry>) -> Self {
+    // inline_elf_paths holds the binary and its debug file
+    pub fn new(bus_rx: BusReader<Entry>, inline_elf_paths: Option<(String, String)>) -> Self {
+        let stack_unwinder = inline_elf_paths.map(|(elf_path, debug_path)| StackUnwinder::new(elf_path, debug_path).unwrap());
         Self { writer: BufWriter::new(File::create("trace.txt").unwrap()), 
-                receiver: BusReceiver { name: "txt".to_string(), bus_rx: bus_rx, checksum: 0 } }
+                receiver: BusReceiver { name: "txt".to_string(), bus_rx: bus_rx, checksum: 0 },
+                stack_unwinder,
+                curr_inline: Vec::new() }
+    }
+
+    // annotate the instruction stream whenever the inline stack changes
+    fn write_inline_change(&mut self, addr: u64) {
+        if let Some(stack_unwinder) = self.stack_unwinder.as_mut() {
+            let inline_frames = stack_unwinder.inline_frames(addr);
+            if *inline_frames == self.curr_inline {
+                return;
+            }
+            self.curr_inline = inline_frames.clone();
+            if self.curr_inline.is_empty() {
+                self.writer.write_all(b"; end of inlined code\n").unwrap();
+            }
+            for frame in self.curr_inline.iter() {
+                self.writer.write_all(format!("; inlined {} at {}:{} ({}:{})\n", frame.name, frame.call_file, frame.call_line, frame.file, frame.line).as_bytes()).unwrap();
+            }
+        }
     }
 }
 
@@ -29,6 +54,7 @@ impl AbstractReceiver for TxtReceiver {
     fn _receive_entry(&mut self, entry: Entry) {
         match entry.event {
             Event::None => {
+                self.write_inline_change(entry.arc.0);
                 // only arc.0 is used for none type events
                 self.writer.write_all(format!("{:#x}:", entry.arc.0).as_bytes()).unwrap();
                 if let Some(insn_mnemonic) = entry.insn_mnemonic {
@@ -44,6 +70,9 @@ impl AbstractReceiver for TxtReceiver {
                     self.writer.write_all(format!("[timestamp: {}]", timestamp).as_bytes()).unwrap();
                     // write the event
                     self.writer.write_all(format!(" {}", entry.event.to_string()).as_bytes()).unwrap();
+                    if entry.cf_kind != ControlFlowKind::None {
+                        self.writer.write_all(format!(" ({})", entry.cf_kind).as_bytes()).unwrap();
+                    }
                     self.writer.write_all(b"\n").unwrap();
                 }
             }
@@ -0,0 +1,280 @@
+use crate::backend::event::Entry;
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::{StackUnwinder, StackFrame, StackUpdate, INTERRUPT_FRAME_ADDR, EXCEPTION_FRAME_ADDR};
+
+use bus::BusReader;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use object::{Object, ObjectSection};
+
+use log::debug;
+
+const UFTRACE_DIR: &str = "uftrace.data";
+
+// the whole trace is a single task of a single process
+const TID: u64 = 1;
+
+// info file header, see uftrace.h
+const UFTRACE_MAGIC: &[u8; 8] = b"Ftrace!\0";
+const UFTRACE_FILE_VERSION: u32 = 4;
+const UFTRACE_HEADER_SIZE: u16 = 40;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_CLASS_64: u8 = 2;
+const MAX_STACK: u16 = 1024;
+
+// feat_mask and info_mask bits
+const FEAT_TASK_SESSION: u64 = 1 << 1;
+const FEAT_MAX_STACK: u64 = 1 << 6;
+const INFO_EXE_NAME: u64 = 1 << 0;
+const INFO_CMDLINE: u64 = 1 << 3;
+const INFO_TASKINFO: u64 = 1 << 7;
+
+// uftrace_record: time, then type:2 more:1 magic:3 depth:10 addr:48
+const RECORD_ENTRY: u64 = 0;
+const RECORD_EXIT: u64 = 1;
+const RECORD_MAGIC: u64 = 0b101;
+const RECORD_ADDR_MASK: u64 = (1 << 48) - 1;
+const RECORD_DEPTH_MASK: u64 = (1 << 10) - 1;
+
+// a uftrace_record: the time in ns, then the packed fields
+fn record(time: u64, record_type: u64, depth: usize, addr: u64) -> [u8; 16] {
+    let fields = record_type
+        | RECORD_MAGIC << 3
+        | (depth as u64 & RECORD_DEPTH_MASK) << 6
+        | (addr & RECORD_ADDR_MASK) << 16;
+    let mut record = [0; 16];
+    record[..8].copy_from_slice(&time.to_le_bytes());
+    record[8..].copy_from_slice(&fields.to_le_bytes());
+    record
+}
+
+// the info file: its header, then one text section per info bit, in the order of the bits
+fn info(exe_name: &Path, cmdline: &str) -> Vec<u8> {
+    let mut info = Vec::new();
+    info.extend_from_slice(UFTRACE_MAGIC);
+    info.extend_from_slice(&UFTRACE_FILE_VERSION.to_le_bytes());
+    info.extend_from_slice(&UFTRACE_HEADER_SIZE.to_le_bytes());
+    info.push(ELF_DATA_LSB);
+    info.push(ELF_CLASS_64);
+    info.extend_from_slice(&(FEAT_TASK_SESSION | FEAT_MAX_STACK).to_le_bytes());
+    info.extend_from_slice(&(INFO_EXE_NAME | INFO_CMDLINE | INFO_TASKINFO).to_le_bytes());
+    info.extend_from_slice(&MAX_STACK.to_le_bytes());
+    // unused
+    info.extend_from_slice(&[0; 6]);
+    info.extend_from_slice(format!("exename:{}\n", exe_name.display()).as_bytes());
+    info.extend_from_slice(format!("cmdline:{}\n", cmdline).as_bytes());
+    info.extend_from_slice(format!("taskinfo:lines=2\ntaskinfo:nr_tid=1\ntaskinfo:tids={}\n", TID).as_bytes());
+    info
+}
+
+// a uftrace data directory, so that uftrace replay, report, graph and tui read
+// the reconstructed calls and returns
+pub struct UftraceReceiver {
+    writer: BufWriter<File>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    elf_path: String,
+    // timestamp ticks per second
+    clock_hz: u64,
+    // where the trap frames are given symbols, past the end of .text
+    text_range: (u64, u64),
+    first_timestamp: Option<u64>,
+    last_timestamp: u64,
+}
+
+impl UftraceReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, clock_hz: u64) -> Self {
+        debug!("UftraceReceiver::new");
+        fs::create_dir_all(UFTRACE_DIR).unwrap();
+        let elf_data = fs::read(&elf_path).unwrap();
+        let elf = object::File::parse(&*elf_data).unwrap();
+        let text_range = elf.section_by_name(".text").map_or((0, 0), |text| (text.address(), text.address() + text.size()));
+        Self {
+            writer: BufWriter::new(File::create(Path::new(UFTRACE_DIR).join(format!("{}.dat", TID))).unwrap()),
+            receiver: BusReceiver {
+                name: "uftrace".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder: StackUnwinder::new(elf_path.clone(), debug_path).unwrap(),
+            elf_path,
+            clock_hz,
+            text_range,
+            first_timestamp: None,
+            last_timestamp: 0,
+        }
+    }
+
+    fn to_ns(&self, timestamp: u64) -> u64 {
+        (timestamp as u128 * 1_000_000_000 / self.clock_hz as u128) as u64
+    }
+
+    // trap frames have no code, they get the addresses right after .text
+    fn record_addr(&self, frame: &StackFrame) -> u64 {
+        match frame.symbol.address {
+            INTERRUPT_FRAME_ADDR => self.text_range.1,
+            EXCEPTION_FRAME_ADDR => self.text_range.1 + 1,
+            addr => addr,
+        }
+    }
+
+    fn write_record(&mut self, record_type: u64, depth: usize, frame: &StackFrame, at: u64) {
+        let record = record(self.to_ns(at), record_type, depth, self.record_addr(frame));
+        self.writer.write_all(&record).unwrap();
+    }
+
+    fn apply(&mut self, update: &StackUpdate, at: u64) {
+        // closed frames come innermost first, opened ones outermost first
+        let depth_before = update.depth + update.closed.len() - update.opened.len();
+        for (i, frame) in update.closed.iter().enumerate() {
+            self.write_record(RECORD_EXIT, depth_before - 1 - i, frame, at);
+        }
+        let depth_closed = depth_before - update.closed.len();
+        for (i, frame) in update.opened.iter().enumerate() {
+            self.write_record(RECORD_ENTRY, depth_closed + i, frame, at);
+        }
+    }
+
+    fn abs_elf_path(&self) -> PathBuf {
+        fs::canonicalize(&self.elf_path).unwrap_or(PathBuf::from(&self.elf_path))
+    }
+
+    // a session id is 16 hex digits, derived from the binary so that it is stable
+    fn session_id(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.abs_elf_path().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn timestamp_text(&self, timestamp: u64) -> String {
+        let ns = self.to_ns(timestamp);
+        format!("{}.{:09}", ns / 1_000_000_000, ns % 1_000_000_000)
+    }
+
+    fn write_info(&self) {
+        let cmdline: Vec<String> = std::env::args().collect();
+        fs::write(Path::new(UFTRACE_DIR).join("info"), info(&self.abs_elf_path(), &cmdline.join(" "))).unwrap();
+    }
+
+    fn write_task(&self, session_id: &str) {
+        let start = self.timestamp_text(self.first_timestamp.unwrap_or(0));
+        let exe_name = self.abs_elf_path();
+        let task = format!(
+            "SESS timestamp={} pid={} sid={} exename=\"{}\"\nTASK timestamp={} tid={} pid={}\n",
+            start, TID, session_id, exe_name.display(), start, TID, TID);
+        fs::write(Path::new(UFTRACE_DIR).join("task.txt"), task).unwrap();
+    }
+
+    // the binary is mapped where it was linked, including the trap frame symbols
+    fn write_map(&self, session_id: &str) {
+        let map = format!("{:08x}-{:08x} r-xp 00000000 00:00 0 {}\n", self.text_range.0, self.text_range.1 + 2, self.abs_elf_path().display());
+        fs::write(Path::new(UFTRACE_DIR).join(format!("sid-{}.map", session_id)), map).unwrap();
+    }
+
+    fn write_symbols(&self) {
+        let exe_name = self.abs_elf_path();
+        let file_name = exe_name.file_name().unwrap().to_string_lossy().to_string();
+        let mut symbols: Vec<(u64, String)> = self.stack_unwinder.func_symbol_map().values()
+            .filter(|symbol| symbol.address < INTERRUPT_FRAME_ADDR)
+            .map(|symbol| (symbol.address, symbol.name.clone()))
+            .collect();
+        symbols.sort();
+        let mut sym = format!("# symbols: {}\n# path name: {}\n", symbols.len() + 2, exe_name.display());
+        for (addr, name) in symbols.iter() {
+            sym.push_str(&format!("{:016x} T {}\n", addr, name));
+        }
+        sym.push_str(&format!("{:016x} T [interrupt]\n", self.text_range.1));
+        sym.push_str(&format!("{:016x} T [exception]\n", self.text_range.1 + 1));
+        // the end of the last symbol
+        sym.push_str(&format!("{:016x} ? __sym_end\n", self.text_range.1 + 2));
+        fs::write(Path::new(UFTRACE_DIR).join(format!("{}.sym", file_name)), sym).unwrap();
+    }
+}
+
+impl AbstractReceiver for UftraceReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.first_timestamp.get_or_insert(timestamp);
+            self.last_timestamp = timestamp;
+        }
+        if StackUnwinder::steps_on(entry.event) {
+            let update = self.stack_unwinder.step(&entry);
+            self.apply(&update, entry.timestamp.unwrap());
+        }
+    }
+
+    fn _flush(&mut self) {
+        // calls still open end with the trace
+        let closed = self.stack_unwinder.flush();
+        self.apply(&StackUpdate { closed, opened: Vec::new(), depth: 0 }, self.last_timestamp);
+        self.writer.flush().unwrap();
+
+        let session_id = self.session_id();
+        self.write_info();
+        self.write_task(&session_id);
+        self.write_map(&session_id);
+        self.write_symbols();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let entry = record(1234, RECORD_ENTRY, 3, 0x8000_1000);
+        assert_eq!(entry[..8], 1234u64.to_le_bytes());
+        // type:2 more:1 magic:3 depth:10 addr:48
+        let fields = u64::from_le_bytes(entry[8..].try_into().unwrap());
+        assert_eq!(fields & 0b11, RECORD_ENTRY);
+        assert_eq!(fields >> 2 & 1, 0);
+        assert_eq!(fields >> 3 & 0b111, 0b101);
+        assert_eq!(fields >> 6 & 0x3ff, 3);
+        assert_eq!(fields >> 16, 0x8000_1000);
+        assert_eq!(entry[8..], [0xe8, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00]);
+
+        let exit = record(0, RECORD_EXIT, 1, 0x1000);
+        assert_eq!(exit[8..], [0x69, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]);
+        // the address and depth are cut to their fields
+        let wide = record(0, RECORD_EXIT, 0x401, u64::MAX);
+        assert_eq!(u64::from_le_bytes(wide[8..].try_into().unwrap()), 0xffff_ffff_ffff_0069);
+    }
+
+    #[test]
+    fn test_info() {
+        let info = info(Path::new("/work/prog.elf"), "ltrace-decoder --to-uftrace");
+        let mut header = Vec::new();
+        header.extend_from_slice(b"Ftrace!\0");
+        // version 4, header size 40, little endian, 64-bit
+        header.extend_from_slice(&[4, 0, 0, 0, 40, 0, 1, 2]);
+        // feat_mask: TASK_SESSION and MAX_STACK
+        header.extend_from_slice(&[0x42, 0, 0, 0, 0, 0, 0, 0]);
+        // info_mask: EXE_NAME, CMDLINE and TASKINFO
+        header.extend_from_slice(&[0x89, 0, 0, 0, 0, 0, 0, 0]);
+        // max_stack, then unused
+        header.extend_from_slice(&[0x00, 0x04, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(header.len(), UFTRACE_HEADER_SIZE as usize);
+        assert_eq!(info[..40], header);
+        assert_eq!(String::from_utf8(info[40..].to_vec()).unwrap(), "\
+exename:/work/prog.elf
+cmdline:ltrace-decoder --to-uftrace
+taskinfo:lines=2
+taskinfo:nr_tid=1
+taskinfo:tids=1
+");
+    }
+}
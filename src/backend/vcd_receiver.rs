@@ -0,0 +1,371 @@
+use crate::backend::event::{Entry, Event};
+use crate::backend::abstract_receiver::{AbstractReceiver, BusReceiver};
+use crate::backend::stack_unwinder::StackUnwinder;
+
+use bus::BusReader;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use log::debug;
+
+// signal identifiers and widths, in the order they are declared
+const PC: &str = "!";
+const FUNC: &str = "\"";
+const DEPTH: &str = "#";
+const MODE: &str = "$";
+const BRANCH: &str = "%";
+const TRAP: &str = "&";
+const TRAP_RETURN: &str = "'";
+const SIGNALS: [(&str, &str, u32); 7] = [
+    (PC, "pc", 64),
+    (FUNC, "func", 32),
+    (DEPTH, "depth", 16),
+    (MODE, "mode", 2),
+    (BRANCH, "branch", 1),
+    (TRAP, "trap", 1),
+    (TRAP_RETURN, "trap_return", 1),
+];
+
+// a VCD timescale is 1, 10 or 100 of a time unit, e.g. 10ns
+pub fn check_timescale(timescale: &str) -> Result<()> {
+    let digits = timescale.trim_end_matches(char::is_alphabetic).trim();
+    let unit = timescale[digits.len()..].trim();
+    if !["1", "10", "100"].contains(&digits) || !["s", "ms", "us", "ns", "ps", "fs"].contains(&unit) {
+        return Err(anyhow::anyhow!("invalid vcd timescale: {}, expected 1, 10 or 100 of s, ms, us, ns, ps or fs", timescale));
+    }
+    Ok(())
+}
+
+fn vector(value: u64) -> String {
+    format!("b{:b} ", value)
+}
+
+// a waveform of the pc, function, call depth and mode with pulses for taken
+// branches and traps, one time step per timestamp tick, for GTKWave
+// function indices are named by a translate filter file written next to it
+pub struct VcdReceiver {
+    writer: Box<dyn Write + Send>,
+    receiver: BusReceiver,
+    stack_unwinder: StackUnwinder,
+    timescale: String,
+    // the time being dumped and the values changing at it
+    time: Option<u64>,
+    changes: BTreeMap<&'static str, String>,
+    // the last value dumped for each signal, and the last time written
+    values: BTreeMap<&'static str, String>,
+    written_time: Option<u64>,
+    // one-bit signals raised at time, lowered one tick later
+    pulses: Vec<&'static str>,
+    // the next instruction is the first since the last timestamp, the pc is dumped for it
+    pc_pending: bool,
+    last_timestamp: u64,
+}
+
+impl VcdReceiver {
+    pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String, timescale: String) -> Self {
+        debug!("VcdReceiver::new");
+        let stack_unwinder = StackUnwinder::new(elf_path, debug_path).unwrap();
+        // gtkwave translate filter: a function index as shown in hex, then its name
+        let mut filter_writer = BufWriter::new(File::create("trace.vcd.func.txt").unwrap());
+        for symbol in stack_unwinder.func_symbol_map().values() {
+            writeln!(filter_writer, "{:x} {}", symbol.index, symbol.name).unwrap();
+        }
+        filter_writer.flush().unwrap();
+        Self::with_unwinder(bus_rx, Box::new(BufWriter::new(File::create("trace.vcd").unwrap())), stack_unwinder, timescale)
+    }
+
+    fn with_unwinder(bus_rx: BusReader<Entry>, writer: Box<dyn Write + Send>, stack_unwinder: StackUnwinder, timescale: String) -> Self {
+        Self {
+            writer,
+            receiver: BusReceiver {
+                name: "vcd".to_string(),
+                bus_rx,
+                checksum: 0,
+            },
+            stack_unwinder,
+            timescale,
+            time: None,
+            changes: BTreeMap::new(),
+            values: BTreeMap::new(),
+            written_time: None,
+            pulses: Vec::new(),
+            pc_pending: false,
+            last_timestamp: 0,
+        }
+    }
+
+    fn write_header(&mut self) {
+        writeln!(self.writer, "$version ltrace-decoder $end").unwrap();
+        writeln!(self.writer, "$timescale {} $end", self.timescale).unwrap();
+        writeln!(self.writer, "$scope module hart0 $end").unwrap();
+        for (id, name, width) in SIGNALS.iter() {
+            if *width == 1 {
+                writeln!(self.writer, "$var wire 1 {} {} $end", id, name).unwrap();
+            } else {
+                writeln!(self.writer, "$var wire {} {} {} [{}:0] $end", width, id, name, width - 1).unwrap();
+            }
+        }
+        writeln!(self.writer, "$upscope $end").unwrap();
+        writeln!(self.writer, "$enddefinitions $end").unwrap();
+    }
+
+    fn write_changes(&mut self) {
+        let Some(time) = self.time else { return };
+        for (id, value) in std::mem::take(&mut self.changes) {
+            if self.values.get(id) == Some(&value) {
+                continue;
+            }
+            if self.written_time != Some(time) {
+                writeln!(self.writer, "#{}", time).unwrap();
+                self.written_time = Some(time);
+            }
+            writeln!(self.writer, "{}{}", value, id).unwrap();
+            self.values.insert(id, value);
+        }
+    }
+
+    // move to timestamp, lowering the pulses of the previous time on the way
+    fn advance(&mut self, timestamp: u64) {
+        let Some(time) = self.time else {
+            // the initial values of all signals
+            self.write_header();
+            self.time = Some(timestamp);
+            self.written_time = Some(timestamp);
+            writeln!(self.writer, "#{}", timestamp).unwrap();
+            writeln!(self.writer, "$dumpvars").unwrap();
+            for (id, _, width) in SIGNALS.iter() {
+                let value = if *width == 1 { String::from("0") } else { String::from("bx ") };
+                writeln!(self.writer, "{}{}", value, id).unwrap();
+                self.values.insert(id, value);
+            }
+            writeln!(self.writer, "$end").unwrap();
+            return;
+        };
+        if timestamp <= time {
+            return;
+        }
+        self.write_changes();
+        // a pulse raised again at timestamp stays high
+        for id in std::mem::take(&mut self.pulses) {
+            self.changes.insert(id, String::from("0"));
+        }
+        if timestamp > time + 1 {
+            self.time = Some(time + 1);
+            self.write_changes();
+        }
+        self.time = Some(timestamp);
+    }
+
+    fn set(&mut self, id: &'static str, value: String) {
+        self.changes.insert(id, value);
+    }
+
+    fn pulse(&mut self, id: &'static str) {
+        self.changes.insert(id, String::from("1"));
+        if !self.pulses.contains(&id) {
+            self.pulses.push(id);
+        }
+    }
+
+    // the function and depth after a stack update
+    fn set_stack(&mut self, depth: usize) {
+        let func = match self.stack_unwinder.stack().last() {
+            Some(frame) => vector(frame.symbol.index as u64),
+            None => String::from("bx "),
+        };
+        self.set(FUNC, func);
+        self.set(DEPTH, vector(depth as u64));
+    }
+}
+
+impl AbstractReceiver for VcdReceiver {
+
+    fn bus_rx(&mut self) -> &mut BusReader<Entry> {
+        &mut self.receiver.bus_rx
+    }
+
+    fn _bump_checksum(&mut self) {
+        self.receiver.checksum += 1;
+    }
+
+    fn _receive_entry(&mut self, entry: Entry) {
+        if let Some(timestamp) = entry.timestamp {
+            self.last_timestamp = self.last_timestamp.max(timestamp);
+            self.advance(self.last_timestamp);
+            self.pc_pending = true;
+            self.set(MODE, vector(entry.context.mode.level()));
+        }
+        match entry.event {
+            Event::None if self.pc_pending => {
+                self.set(PC, vector(entry.arc.0));
+                self.pc_pending = false;
+            }
+            Event::TakenBranch => self.pulse(BRANCH),
+            Event::End => {
+                // nothing is known until the next start
+                let update = self.stack_unwinder.step(&entry);
+                self.set_stack(update.depth);
+                self.set(PC, String::from("bx "));
+                self.pc_pending = false;
+            }
+            event if StackUnwinder::steps_on(event) => {
+                match entry.event {
+                    Event::TrapException | Event::TrapInterrupt => self.pulse(TRAP),
+                    Event::TrapReturn => self.pulse(TRAP_RETURN),
+                    _ => {}
+                }
+                let update = self.stack_unwinder.step(&entry);
+                self.set_stack(update.depth);
+            }
+            _ => {}
+        }
+    }
+
+    fn _flush(&mut self) {
+        // lower the last pulses, or write the header and initial values of an empty trace
+        self.advance(self.last_timestamp + 1);
+        self.write_changes();
+        self.writer.flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::event::{ControlFlowKind, PrivMode};
+    use crate::backend::stack_unwinder::SymbolInfo;
+
+    use indexmap::IndexMap;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const MAIN: u64 = 0x1000;
+    const F: u64 = 0x1100;
+
+    // the dump as written, kept after the receiver is done with it
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn dump(entries: Vec<Entry>) -> String {
+        let mut func_symbol_map = IndexMap::new();
+        for (index, (address, name)) in [(MAIN, "main"), (F, "f")].into_iter().enumerate() {
+            func_symbol_map.insert(address, SymbolInfo { address, name: name.to_string(), mangled_name: name.to_string(), index: index as u32, line: 0, file: String::new() });
+        }
+        let vcd = SharedBuf::default();
+        let stack_unwinder = StackUnwinder::from_maps(func_symbol_map, HashMap::new(), None);
+        let mut receiver = VcdReceiver::with_unwinder(bus::Bus::new(1).add_rx(), Box::new(vcd.clone()), stack_unwinder, "10ns".to_string());
+        for entry in entries {
+            receiver._receive_entry(entry);
+        }
+        receiver._flush();
+        let vcd = vcd.0.lock().unwrap().clone();
+        String::from_utf8(vcd).unwrap()
+    }
+
+    fn insn(pc: u64) -> Entry {
+        let mut entry = Entry::new_timed_event(Event::None, 0, pc, 0);
+        entry.timestamp = None;
+        entry
+    }
+
+    const HEADER: &str = "\
+$version ltrace-decoder $end
+$timescale 10ns $end
+$scope module hart0 $end
+$var wire 64 ! pc [63:0] $end
+$var wire 32 \" func [31:0] $end
+$var wire 16 # depth [15:0] $end
+$var wire 2 $ mode [1:0] $end
+$var wire 1 % branch $end
+$var wire 1 & trap $end
+$var wire 1 ' trap_return $end
+$upscope $end
+$enddefinitions $end
+";
+
+    #[test]
+    fn test_check_timescale() {
+        for timescale in ["1s", "10ns", "100ps", "1 us", "10 fs"] {
+            assert!(check_timescale(timescale).is_ok(), "{}", timescale);
+        }
+        for timescale in ["3ns", "1000ns", "10xs", "ns", "10", "", "10nss"] {
+            assert!(check_timescale(timescale).is_err(), "{}", timescale);
+        }
+    }
+
+    #[test]
+    fn test_empty_trace() {
+        assert_eq!(dump(Vec::new()), HEADER.to_string() + "\
+#1
+$dumpvars
+bx !
+bx \"
+bx #
+bx $
+0%
+0&
+0'
+$end
+");
+    }
+
+    #[test]
+    fn test_changes_and_pulses() {
+        let mut user = Entry::new_timed_event(Event::TakenBranch, 5, F + 0x8, F + 0x20);
+        user.context.mode = PrivMode::U;
+        let vcd = dump(vec![
+            Entry::new_timed_event(Event::Start, 2, MAIN, 0),
+            insn(MAIN),
+            Entry::new_timed_jump(Event::InferrableJump, ControlFlowKind::Call, 3, MAIN + 0x4, F),
+            insn(F),
+            user,
+            insn(F + 0x20),
+            Entry::new_timed_event(Event::TrapException, 6, F + 0x24, MAIN + 0x80),
+        ]);
+        assert_eq!(vcd, HEADER.to_string() + "\
+#2
+$dumpvars
+bx !
+bx \"
+bx #
+bx $
+0%
+0&
+0'
+$end
+b1000000000000 !
+b0 \"
+b1 #
+b11 $
+#3
+b1000100000000 !
+b1 \"
+b10 #
+#5
+b1000100100000 !
+b0 $
+1%
+#6
+b0 \"
+b100 #
+b11 $
+0%
+1&
+#7
+0&
+");
+    }
+}
@@ -25,7 +25,7 @@ pub struct VPPReceiver {
 }
 
 impl VPPReceiver {
-  pub fn new(bus_rx: BusReader<Entry>, elf_path: String) -> Self {
+  pub fn new(bus_rx: BusReader<Entry>, elf_path: String, debug_path: String) -> Self {
     debug!("Creating VPPReceiver");
     Self {
       writer: BufWriter::new(File::create("trace.vpp.txt").unwrap()),
@@ -34,7 +34,7 @@ impl VPPReceiver {
         bus_rx,
         checksum: 0,
       },
-      stack_unwinder: StackUnwinder::new(elf_path).unwrap(),
+      stack_unwinder: StackUnwinder::new(elf_path, debug_path).unwrap(),
       path_records: HashMap::new(),
       curr_path: None,
       start_timestamp: 0,
@@ -53,42 +53,41 @@ impl AbstractReceiver for VPPReceiver {
 
   fn _receive_entry(&mut self, entry: Entry) {
     match entry.event {
-      Event::InferrableJump => {
-        let (success, frame_stack_size, _) = self.stack_unwinder.step_ij(entry.clone());
-        if success && frame_stack_size == 1 {
-          debug!("Starting new path on address {:#x}", entry.arc.1);
-          self.curr_path = Some(Path {
-            addr: entry.arc.1,
-            path: Vec::new(),
-          });
-          self.start_timestamp = entry.timestamp.unwrap();
-        }
-      }
-      Event::UninferableJump => {
-        let (success, frame_stack_size, _) = self.stack_unwinder.step_uj(entry.clone());
-        debug!("frame_stack_size: {}", frame_stack_size);
-        if success && (frame_stack_size == 0) {
-          debug!("frame_stack_size is {}", frame_stack_size);
-          if let Some(curr_path) = self.curr_path.as_ref() {
+      Event::InferrableJump | Event::UninferableJump => {
+        let update = self.stack_unwinder.step(&entry);
+        // a path spans one call of an outermost function, inferred callers
+        // (whose entry we never saw) do not count
+        let depth = update.depth - self.stack_unwinder.inferred_depth();
+        // jumps open at most one frame
+        let opened = update.opened.into_iter().find(|frame| !frame.inferred);
+        let depth_after_close = depth - opened.is_some() as usize;
+        debug!("frame_stack_size: {}", depth);
+        if update.closed.iter().any(|frame| !frame.inferred) && depth_after_close == 0 {
+          if let Some(curr_path) = self.curr_path.take() {
             debug!("Closing path on current path {:#x}", curr_path.addr);
+            // if curr_path is contained in path_records, add the time interval to the record
+            // otherwise, create a new record
+            self.path_records.entry(curr_path).or_default().push(entry.timestamp.unwrap() - self.start_timestamp);
           } else {
             debug!("No current path");
           }
-          debug!("Hello");
-          let curr_path_ref = self.curr_path.as_ref();
-          let curr_path_unwraped = curr_path_ref.unwrap();
-          debug!("Closing path on current path {:#x}", curr_path_unwraped.addr);
-          // if curr_path is contained in path_records, add the time interval to the record
-          if let Some(path_record) = self.path_records.get_mut(&self.curr_path.as_ref().unwrap()) {
-            path_record.push(entry.timestamp.unwrap() - self.start_timestamp);
-          }
-          // otherwise, create a new record
-          else {
-            self.path_records.insert(self.curr_path.as_ref().unwrap().clone(), vec![entry.timestamp.unwrap() - self.start_timestamp]);
+        }
+        if let Some(opened) = opened {
+          if depth == 1 {
+            debug!("Starting new path on address {:#x}", opened.symbol.address);
+            self.curr_path = Some(Path {
+              addr: opened.symbol.address,
+              path: Vec::new(),
+            });
+            self.start_timestamp = entry.timestamp.unwrap();
           }
-          self.curr_path = None;
         }
       }
+      Event::End => {
+        // a path cut off by the end of a region is incomplete
+        self.curr_path = None;
+        self.stack_unwinder.step(&entry);
+      }
       Event::TakenBranch => {
         if let Some(curr_path) = self.curr_path.as_mut() {
           curr_path.path.push(true);
@@ -99,6 +98,11 @@ impl AbstractReceiver for VPPReceiver {
           curr_path.path.push(false);
         }
       }
+      // the start bootstraps the stack with the function the trace starts in,
+      // traps and task switches keep it in step
+      event if StackUnwinder::steps_on(event) => {
+        self.stack_unwinder.step(&entry);
+      }
       _ => {
         // ignore other events
       }
@@ -0,0 +1,54 @@
+use log::{debug, warn};
+
+use crate::backend::event::{Context, Entry, Event, PrivMode};
+
+// follows the privilege level and address space through traps and reported values
+// a trap moves to trap_mode and its return back to the mode it was taken from,
+// a reported mode (e.g. a trap delegated to S) overrides either
+pub struct ContextTracker {
+    context: Context,
+    // the mode before each trap still being handled, innermost last
+    mode_stack: Vec<PrivMode>,
+    // the mode returned to if the trace starts inside a handler
+    start_mode: PrivMode,
+    trap_mode: PrivMode,
+}
+
+impl ContextTracker {
+    pub fn new(start_mode: PrivMode, trap_mode: PrivMode) -> Self {
+        Self { context: Context { mode: start_mode, asid: None }, mode_stack: Vec::new(), start_mode, trap_mode }
+    }
+
+    pub fn context(&self) -> Context {
+        self.context
+    }
+
+    // update the context from an entry, then stamp the entry with it
+    pub fn stamp(&mut self, entry: &mut Entry) {
+        let prev = self.context;
+        match entry.event {
+            Event::TrapException | Event::TrapInterrupt => {
+                self.mode_stack.push(self.context.mode);
+                self.context.mode = self.trap_mode;
+            }
+            Event::TrapReturn => {
+                self.context.mode = self.mode_stack.pop().unwrap_or(self.start_mode);
+            }
+            Event::ModeChange => {
+                // a corrupt level says nothing about the mode, keep the one we know
+                match PrivMode::from_level(entry.arc.1) {
+                    Ok(mode) => self.context.mode = mode,
+                    Err(err) => warn!("[context] {} at {:#x}, staying in {}-mode", err, entry.arc.0, self.context.mode),
+                }
+            }
+            Event::AsidChange => {
+                self.context.asid = Some(entry.arc.1);
+            }
+            _ => {}
+        }
+        if self.context != prev {
+            debug!("[context] {} -> {} at {:#x}", prev, self.context, entry.arc.0);
+        }
+        entry.context = self.context;
+    }
+}
@@ -1,13 +1,14 @@
-use std::fs::File;
-use std::io::{Read, BufReader};
-use anyhow::Result;
-use log::trace;
+use std::io::{Read, Write};
+use anyhow::{anyhow, Result};
+use log::{trace, warn};
 const C_HEADER_MASK: u8 = 0b0000_0011;
 const C_TIMESTAMP_MASK: u8 = 0b1111_1100;
 const F_HEADER_MASK: u8 = 0b0001_1100;
 const FHEADER_OFFSET: u8 = 2;
 const TRAP_TYPE_MASK: u8 = 0b1110_0000;
 const TRAP_TYPE_OFFSET: u8 = 5;
+const VAL_TYPE_MASK: u8 = 0b1110_0000;
+const VAL_TYPE_OFFSET: u8 = 5;
 
 const VAR_MASK: u8 = 0b1000_0000;
 // const VAR_CONT: u8 = 0b0000_0000;
@@ -15,7 +16,7 @@ const VAR_LAST: u8 = 0b1000_0000;
 const VAR_OFFSET: u8 = 7;
 const VAR_VAL_MASK: u8 = 0b0111_1111;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CHeader {
     CTb = 0b00, // taken branch
     CNt = 0b01, // not taken branch
@@ -74,7 +75,7 @@ impl From<CHeader> for FHeader {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrapType {
     TNone      = 0b000,
     TException = 0b001,
@@ -94,7 +95,37 @@ impl From<u8> for TrapType {
     }
 }
 
-#[derive(Debug)]
+// which value an FVal packet reports
+// an FVal packet is the header byte [val_type:3][FVal:3][CNa:2], then the value
+// and the timestamp delta as varints. the layout does not depend on val_type, so
+// packets with an unknown val_type can be skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValType {
+    VNone  = 0b000,
+    VCause = 0b001, // cause of the last trap (xcause)
+    VTval  = 0b010, // trap value of the last trap (xtval), e.g. the faulting address
+    VAsid  = 0b011, // address space switched to (satp.ASID)
+    VMode  = 0b100, // privilege level switched to, 0 = U, 1 = S, 3 = M
+    VTask  = 0b101, // task switched to, an id chosen by the OS (e.g. a TCB address or pid)
+}
+
+impl TryFrom<u8> for ValType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0b000 => Ok(ValType::VNone),
+            0b001 => Ok(ValType::VCause),
+            0b010 => Ok(ValType::VTval),
+            0b011 => Ok(ValType::VAsid),
+            0b100 => Ok(ValType::VMode),
+            0b101 => Ok(ValType::VTask),
+            _ => Err(anyhow!("unknown value packet type: {:#05b}", value)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Packet {
     pub is_compressed: bool,
     pub c_header: CHeader,
@@ -102,12 +133,14 @@ pub struct Packet {
     pub trap_type: TrapType,
     pub target_address: u64,
     pub trap_address: u64,
+    pub val_type: ValType,
+    pub value: u64,
     pub timestamp: u64,
 }
 
 // Initialize a packet with default values
 impl Packet {
-    fn new() -> Packet {
+    pub fn new() -> Packet {
         Packet {
             is_compressed: false,
             c_header: CHeader::CNa,
@@ -115,18 +148,20 @@ impl Packet {
             trap_type: TrapType::TNone,
             target_address: 0,
             trap_address: 0,
+            val_type: ValType::VNone,
+            value: 0,
             timestamp: 0,
         }
     }
 }
 
-fn read_u8(stream: &mut BufReader<File>) -> Result<u8> {
+fn read_u8(stream: &mut impl Read) -> Result<u8> {
     let mut buf = [0u8; 1];
     stream.read_exact(&mut buf)?;
     Ok(buf[0])
 }
 
-fn read_varint(stream: &mut BufReader<File>) -> Result<u64> {
+fn read_varint(stream: &mut impl Read) -> Result<u64> {
     let mut result = Vec::new();
     loop {
         let byte = read_u8(stream)?;
@@ -136,7 +171,7 @@ fn read_varint(stream: &mut BufReader<File>) -> Result<u64> {
     Ok(result.iter().rev().fold(0, |acc, &x| (acc << VAR_OFFSET) | (x & VAR_VAL_MASK) as u64))
 } 
 
-pub fn read_packet(stream: &mut BufReader<File>) -> Result<Packet> {
+pub fn read_packet(stream: &mut impl Read) -> Result<Packet> {
     let mut packet = Packet::new();
     let first_byte = read_u8(stream)?;
     trace!("first_byte: {:08b}", first_byte);
@@ -187,6 +222,19 @@ pub fn read_packet(stream: &mut BufReader<File>) -> Result<Packet> {
                     packet.f_header = f_header;
                     packet.c_header = CHeader::CNa;
                 }
+                FHeader::FVal => {
+                    // left as VNone, its value is read and dropped
+                    packet.val_type = ValType::try_from((first_byte & VAL_TYPE_MASK) >> VAL_TYPE_OFFSET).unwrap_or_else(|err| {
+                        warn!("{}, skipping it", err);
+                        ValType::VNone
+                    });
+                    let value = read_varint(stream)?;
+                    packet.value = value;
+                    let timestamp = read_varint(stream)?;
+                    packet.timestamp = timestamp;
+                    packet.f_header = f_header;
+                    packet.c_header = CHeader::CNa;
+                }
                 _ => {
                     println!("Invalid FHeader value: {}", first_byte);
                 }
@@ -195,3 +243,157 @@ pub fn read_packet(stream: &mut BufReader<File>) -> Result<Packet> {
     }
     Ok(packet)
 }
+
+fn write_varint(stream: &mut impl Write, value: u64) -> Result<()> {
+    let mut value = value;
+    loop {
+        let byte = value as u8 & VAR_VAL_MASK;
+        value >>= VAR_OFFSET;
+        if value == 0 {
+            stream.write_all(&[byte | VAR_LAST])?;
+            return Ok(());
+        }
+        stream.write_all(&[byte])?;
+    }
+}
+
+// inverse of read_packet
+pub fn write_packet(stream: &mut impl Write, packet: &Packet) -> Result<()> {
+    if packet.is_compressed {
+        let first_byte = ((packet.timestamp as u8) << 2) & C_TIMESTAMP_MASK | packet.c_header.clone() as u8;
+        stream.write_all(&[first_byte])?;
+        return Ok(());
+    }
+    let f_header_byte = (packet.f_header.clone() as u8) << FHEADER_OFFSET | CHeader::CNa as u8;
+    match packet.f_header {
+        FHeader::FTb | FHeader::FNt | FHeader::FIj => {
+            stream.write_all(&[f_header_byte])?;
+            write_varint(stream, packet.timestamp)?;
+        }
+        FHeader::FUj | FHeader::FSync => {
+            stream.write_all(&[f_header_byte])?;
+            write_varint(stream, packet.target_address)?;
+            write_varint(stream, packet.timestamp)?;
+        }
+        FHeader::FTrap => {
+            stream.write_all(&[(packet.trap_type as u8) << TRAP_TYPE_OFFSET | f_header_byte])?;
+            write_varint(stream, packet.trap_address)?;
+            write_varint(stream, packet.target_address)?;
+            write_varint(stream, packet.timestamp)?;
+        }
+        FHeader::FVal => {
+            stream.write_all(&[(packet.val_type as u8) << VAL_TYPE_OFFSET | f_header_byte])?;
+            write_varint(stream, packet.value)?;
+            write_varint(stream, packet.timestamp)?;
+        }
+        FHeader::FRes => {
+            return Err(anyhow::anyhow!("cannot write a reserved packet"));
+        }
+    }
+    Ok(())
+}
+
+// a sync packet, which also starts a trace: an absolute pc and timestamp
+pub fn sync_packet(pc: u64, timestamp: u64) -> Packet {
+    let mut packet = Packet::new();
+    packet.f_header = FHeader::FSync;
+    packet.target_address = pc >> 1;
+    packet.timestamp = timestamp;
+    packet
+}
+
+// a reported value, taking no time
+pub fn value_packet(val_type: ValType, value: u64) -> Packet {
+    let mut packet = Packet::new();
+    packet.f_header = FHeader::FVal;
+    packet.val_type = val_type;
+    packet.value = value;
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed_packet(c_header: CHeader, timestamp: u64) -> Packet {
+        let mut packet = Packet::new();
+        packet.is_compressed = true;
+        packet.f_header = FHeader::from(c_header.clone());
+        packet.c_header = c_header;
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    fn full_packet(f_header: FHeader, target_address: u64, timestamp: u64) -> Packet {
+        let mut packet = Packet::new();
+        packet.f_header = f_header;
+        packet.target_address = target_address;
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    fn trap_packet(trap_type: TrapType, trap_address: u64, target_address: u64, timestamp: u64) -> Packet {
+        let mut packet = full_packet(FHeader::FTrap, target_address, timestamp);
+        packet.trap_type = trap_type;
+        packet.trap_address = trap_address;
+        packet
+    }
+
+    fn timed_value_packet(val_type: ValType, value: u64, timestamp: u64) -> Packet {
+        let mut packet = value_packet(val_type, value);
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packets = vec![
+            sync_packet(0x8000_0000, 0),
+            compressed_packet(CHeader::CTb, 0),
+            compressed_packet(CHeader::CNt, 5),
+            compressed_packet(CHeader::CIj, 63),
+            full_packet(FHeader::FTb, 0, 64),
+            full_packet(FHeader::FNt, 0, 1 << 40),
+            full_packet(FHeader::FIj, 0, u64::MAX),
+            full_packet(FHeader::FUj, 0x4000_0010, 7),
+            trap_packet(TrapType::TException, 0x8000_0024, 0x10, 3),
+            trap_packet(TrapType::TInterrupt, 0x8000_0008, 0x4000_0100, 0),
+            trap_packet(TrapType::TReturn, 0x8000_0040, 0x1c, 200),
+            timed_value_packet(ValType::VNone, 0, 0),
+            timed_value_packet(ValType::VCause, 0x8000_0000_0000_0007, 1),
+            timed_value_packet(ValType::VTval, 0xdead_beef, 0),
+            timed_value_packet(ValType::VAsid, 9, 2),
+            timed_value_packet(ValType::VMode, 3, 0),
+            timed_value_packet(ValType::VTask, 0x8000_1230, 0),
+            sync_packet(0x8000_0100, 500),
+        ];
+        let mut encoded = Vec::new();
+        for packet in packets.iter() {
+            write_packet(&mut encoded, packet).unwrap();
+        }
+        let mut stream = &encoded[..];
+        for packet in packets.iter() {
+            assert_eq!(read_packet(&mut stream).unwrap(), *packet);
+        }
+        assert!(stream.is_empty());
+        assert!(read_packet(&mut stream).is_err());
+    }
+
+    #[test]
+    fn test_reserved_packet_is_not_written() {
+        let mut encoded = Vec::new();
+        assert!(write_packet(&mut encoded, &Packet::new()).is_err());
+    }
+
+    #[test]
+    fn test_unknown_value_type_is_skipped() {
+        // val_type 0b111, value 5, timestamp 3, then a taken branch
+        let encoded = [0b111 << VAL_TYPE_OFFSET | (FHeader::FVal as u8) << FHEADER_OFFSET | CHeader::CNa as u8, 0x85, 0x83, 0b0000_0100];
+        let mut stream = &encoded[..];
+        let packet = read_packet(&mut stream).unwrap();
+        assert_eq!(packet.f_header, FHeader::FVal);
+        assert_eq!(packet.val_type, ValType::VNone);
+        assert_eq!((packet.value, packet.timestamp), (5, 3));
+        assert_eq!(read_packet(&mut stream).unwrap(), compressed_packet(CHeader::CTb, 1));
+    }
+}
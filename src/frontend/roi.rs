@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+
+use bus::Bus;
+use log::debug;
+
+use crate::backend::event::{Entry, Event};
+use crate::frontend::context::ContextTracker;
+
+// what makes up a region of interest
+// markers open and close regions, windows bound where regions may be at all
+#[derive(Default)]
+pub struct Roi {
+    // a region opens when the instruction at one of these is executed
+    pub start_addrs: HashSet<u64>,
+    // and closes right before the instruction at one of these
+    pub stop_addrs: HashSet<u64>,
+    // [start, end) in timestamps and in executed instructions
+    pub time_window: (Option<u64>, Option<u64>),
+    pub insn_window: (Option<u64>, Option<u64>),
+}
+
+impl Roi {
+    fn in_window(window: (Option<u64>, Option<u64>), value: u64) -> bool {
+        window.0.is_none_or(|start| value >= start) && window.1.is_none_or(|end| value < end)
+    }
+}
+
+// sits between the frontend and the bus, forwarding only entries inside a region
+// each region is framed by a Start and an End entry, both carrying the region
+// number (counting from 0) in arc.1, so receivers can tell iterations apart
+// entries are stamped with their context on the way, whether forwarded or not
+pub struct RoiGate {
+    bus: Bus<Entry>,
+    roi: Roi,
+    context: ContextTracker,
+    // whether a start marker was seen more recently than a stop marker
+    marker_open: bool,
+    active: bool,
+    region: u64,
+    insn_count: u64,
+    timestamp: u64,
+}
+
+impl RoiGate {
+    pub fn new(bus: Bus<Entry>, roi: Roi, context: ContextTracker) -> Self {
+        // without start markers, regions are only bounded by stop markers and windows
+        let marker_open = roi.start_addrs.is_empty();
+        Self { bus, roi, context, marker_open, active: false, region: 0, insn_count: 0, timestamp: 0 }
+    }
+
+    pub fn broadcast(&mut self, entry: Entry) {
+        let mut entry = entry;
+        self.context.stamp(&mut entry);
+        match entry.event {
+            Event::Start => {
+                self.timestamp = entry.timestamp.unwrap();
+                self.update(entry.arc.0);
+            }
+            Event::End => {
+                self.timestamp = entry.timestamp.unwrap();
+                self.close(entry.arc.0);
+            }
+            Event::None => {
+                let addr = entry.arc.0;
+                if self.roi.start_addrs.contains(&addr) {
+                    self.marker_open = true;
+                }
+                if self.roi.stop_addrs.contains(&addr) {
+                    self.marker_open = false;
+                }
+                self.update(addr);
+                if self.active {
+                    self.bus.broadcast(entry);
+                }
+                self.insn_count += 1;
+            }
+            _ => {
+                if let Some(timestamp) = entry.timestamp {
+                    self.timestamp = timestamp;
+                }
+                if self.active {
+                    self.bus.broadcast(entry);
+                }
+            }
+        }
+    }
+
+    // close the last region if the trace ends without an End
+    pub fn finish(&mut self, pc: u64) {
+        self.close(pc);
+    }
+
+    fn update(&mut self, pc: u64) {
+        let active = self.marker_open
+            && Roi::in_window(self.roi.time_window, self.timestamp)
+            && Roi::in_window(self.roi.insn_window, self.insn_count);
+        if active && !self.active {
+            debug!("[roi] region {} opens at {:#x}", self.region, pc);
+            let mut start = Entry::new_timed_event(Event::Start, self.timestamp, pc, self.region);
+            start.context = self.context.context();
+            self.bus.broadcast(start);
+            self.active = true;
+        } else if !active {
+            self.close(pc);
+        }
+    }
+
+    fn close(&mut self, pc: u64) {
+        if self.active {
+            debug!("[roi] region {} closes at {:#x}", self.region, pc);
+            let mut end = Entry::new_timed_event(Event::End, self.timestamp, pc, self.region);
+            end.context = self.context.context();
+            self.bus.broadcast(end);
+            self.active = false;
+            self.region += 1;
+        }
+    }
+}
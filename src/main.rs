@@ -7,6 +7,8 @@ extern crate env_logger;
 extern crate gcno_reader;
 mod frontend {
     pub mod packet;
+    pub mod roi;
+    pub mod context;
 }
 mod backend {
     pub mod abstract_receiver;
@@ -18,26 +20,44 @@ mod backend {
     pub mod stack_unwinder;
     pub mod speedscope_receiver;
     pub mod vpp_receiver;
+    pub mod debug_info;
+    pub mod cfi_receiver;
+    pub mod indirect_receiver;
+    pub mod flight_recorder_receiver;
+    pub mod backtrace_receiver;
+    pub mod filter;
+    pub mod chrome_trace_receiver;
+    pub mod folded_receiver;
+    pub mod callgrind_receiver;
+    pub mod pprof_receiver;
+    pub mod firefox_receiver;
+    pub mod ctf_receiver;
+    pub mod vcd_receiver;
+    pub mod function_graph_receiver;
+    pub mod uftrace_receiver;
+    pub mod cost_accounting;
 }
 
-use frontend::packet::FHeader;
+use frontend::packet::{FHeader, Packet, ValType};
+use frontend::roi::{Roi, RoiGate};
+use frontend::context::ContextTracker;
 
 // file IO
 use std::fs::File;
-use std::io::{Read, BufReader};
+use std::io::{Read, Write, BufReader, BufWriter};
 // collections 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 // argparse dependency
-use clap::Parser;
+use clap::{Parser, Subcommand};
 // objdump dependency
 use capstone::prelude::*;
 use capstone::arch::riscv::{ArchMode, ArchExtraMode};
-use capstone::Insn;
-use object::{Object, ObjectSection};
+use capstone::{Insn, Instructions};
+use object::{Object, ObjectSection, ObjectSymbol};
 // bus dependency
 use bus::Bus;
 use std::thread;
-use backend::event::{Entry, Event};
+use backend::event::{Entry, Event, ControlFlowKind, PrivMode, SplitBy};
 use backend::txt_receiver::TxtReceiver;
 use backend::json_receiver::JsonReceiver;
 use backend::afdo_receiver::AfdoReceiver;
@@ -45,6 +65,22 @@ use backend::abstract_receiver::AbstractReceiver;
 use backend::gcda_receiver::GcdaReceiver;
 use backend::speedscope_receiver::SpeedscopeReceiver;
 use backend::vpp_receiver::VPPReceiver;
+use backend::cfi_receiver::CfiReceiver;
+use backend::indirect_receiver::IndirectReceiver;
+use backend::flight_recorder_receiver::FlightRecorderReceiver;
+use backend::backtrace_receiver::BacktraceReceiver;
+use backend::chrome_trace_receiver::ChromeTraceReceiver;
+use backend::folded_receiver::{FoldedReceiver, FoldedWeight};
+use backend::callgrind_receiver::CallgrindReceiver;
+use backend::pprof_receiver::PprofReceiver;
+use backend::firefox_receiver::FirefoxReceiver;
+use backend::ctf_receiver::CtfReceiver;
+use backend::vcd_receiver::VcdReceiver;
+use backend::function_graph_receiver::FunctionGraphReceiver;
+use backend::uftrace_receiver::UftraceReceiver;
+use backend::debug_info;
+use backend::filter;
+use backend::stack_unwinder::demangle_name;
 // error handling
 use anyhow::Result;
 // logging
@@ -71,9 +107,15 @@ struct Args {
     // print the timestamp in the decoded trace file
     #[arg(short, long, default_value_t = false)]
     timestamp: bool,
+    // directories to search for separate debug info (build-id tree and .gnu_debuglink)
+    #[arg(long, default_values_t = vec![String::from("/usr/lib/debug")])]
+    debug_dir: Vec<String>,
     // output the decoded trace in text format
     #[arg(long, default_value_t = true)]
     to_txt: bool,
+    // annotate the text output with inlined functions
+    #[arg(long, default_value_t = false)]
+    txt_inline: bool,
     // output the decoded trace in JSON format
     #[arg(long, default_value_t = false)]
     to_json: bool,
@@ -92,6 +134,161 @@ struct Args {
     // output the decoded trace in vpp format
     #[arg(long, default_value_t = false)]
     to_vpp: bool,
+    // check the trace for control-flow integrity violations
+    #[arg(long, default_value_t = false)]
+    to_cfi: bool,
+    // profile the targets of indirect calls and jumps, and derive an allow-list
+    #[arg(long, default_value_t = false)]
+    to_indirect: bool,
+    // report the last basic blocks and the backtrace on exceptions and triggers
+    #[arg(long, default_value_t = false)]
+    to_flight_recorder: bool,
+    // number of basic blocks the flight recorder keeps, at least one
+    #[arg(long, default_value_t = 64, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    flight_recorder_blocks: usize,
+    // also report when reaching these symbols or addresses (0x...)
+    #[arg(long)]
+    flight_recorder_trigger: Vec<String>,
+    // report the call stack and the last trap at the end of the trace
+    #[arg(long, default_value_t = false)]
+    to_backtrace: bool,
+    // output function durations and traps in the Chrome trace event format, for Perfetto
+    #[arg(long, default_value_t = false)]
+    to_chrome_trace: bool,
+    // timestamp ticks per second, to convert timestamps into wall time
+    #[arg(long, default_value_t = 1_000_000, value_parser = clap::value_parser!(u64).range(1..))]
+    clock_hz: u64,
+    // output collapsed stacks for flame graphs
+    #[arg(long, default_value_t = false)]
+    to_folded: bool,
+    // weight collapsed stacks by timestamp ticks or by executed instructions
+    #[arg(long, value_enum, default_value_t = FoldedWeight::Cycles)]
+    folded_weight: FoldedWeight,
+    // add the source line as the leaf frame of collapsed stacks
+    #[arg(long, default_value_t = false)]
+    folded_lines: bool,
+    // output instruction and cycle costs with call edges in the callgrind format, for KCachegrind
+    #[arg(long, default_value_t = false)]
+    to_callgrind: bool,
+    // output instructions and cycles per stack as a gzipped pprof profile
+    #[arg(long, default_value_t = false)]
+    to_pprof: bool,
+    // output sampled stacks with trap and region markers for the Firefox Profiler
+    #[arg(long, default_value_t = false)]
+    to_firefox: bool,
+    // timestamp ticks between two samples of the Firefox Profiler output
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    sample_interval: u64,
+    // output function entries and exits, branches, traps and values as a CTF trace, for Trace Compass
+    #[arg(long, default_value_t = false)]
+    to_ctf: bool,
+    // output the pc, function, call depth, mode, branches and traps as a VCD waveform, for GTKWave
+    #[arg(long, default_value_t = false)]
+    to_vcd: bool,
+    // what one timestamp tick is in the VCD waveform, e.g. 1us or 10ns
+    #[arg(long, default_value = "1us")]
+    vcd_timescale: String,
+    // output calls and returns as indented text like the ftrace function_graph tracer
+    #[arg(long, default_value_t = false)]
+    to_function_graph: bool,
+    // add the hart column to the function graph
+    #[arg(long, default_value_t = false)]
+    graph_hart: bool,
+    // only print calls less deep than this in the function graph
+    #[arg(long)]
+    graph_max_depth: Option<usize>,
+    // only print calls lasting at least this many timestamp ticks in the function graph
+    #[arg(long, default_value_t = 0)]
+    graph_min_cycles: u64,
+    // output calls and returns as a uftrace data directory, for uftrace replay, report, graph and tui
+    #[arg(long, default_value_t = false)]
+    to_uftrace: bool,
+    // symbols or addresses (0x...) opening a region of interest, receivers only see regions
+    #[arg(long)]
+    roi_start: Vec<String>,
+    // symbols or addresses (0x...) closing a region of interest
+    #[arg(long)]
+    roi_stop: Vec<String>,
+    // only keep regions within [roi_time_start, roi_time_end) timestamps
+    #[arg(long)]
+    roi_time_start: Option<u64>,
+    #[arg(long)]
+    roi_time_end: Option<u64>,
+    // only keep regions within [roi_insn_start, roi_insn_end) executed instructions
+    #[arg(long)]
+    roi_insn_start: Option<u64>,
+    #[arg(long)]
+    roi_insn_end: Option<u64>,
+    // per-receiver filters as receiver=rule,..., e.g. txt=+sym:crypto_*,-0x80001000-0x80002000
+    // rules: +/- followed by an address range, sym:<glob>, tree:<glob>, time:<start>-<end>,
+    // mode:<U|S|M> or asid:<n>
+    #[arg(long)]
+    filter: Vec<String>,
+    // privilege mode the trace starts in
+    #[arg(long, value_enum, default_value_t = PrivMode::M)]
+    start_mode: PrivMode,
+    // privilege mode traps are taken into, unless the trace reports otherwise
+    #[arg(long, value_enum, default_value_t = PrivMode::M)]
+    trap_mode: PrivMode,
+    // keep separate speedscope and afdo profiles per privilege mode, address space or both
+    #[arg(long, value_enum, default_value_t = SplitBy::None)]
+    split_by: SplitBy,
+    // context-switch functions (symbols or 0x addresses), call stacks are then kept per task
+    // e.g. __switch_to, or vTaskSwitchContext whose trap handler returns into the next task
+    #[arg(long)]
+    switch_symbol: Vec<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    // cut a window out of the encoded trace into a trace that decodes on its own
+    Slice(SliceArgs),
+}
+
+#[derive(Clone, clap::Args)]
+struct SliceArgs {
+    // path to the sliced trace file
+    #[arg(short, long, default_value_t = String::from("trace.slice"))]
+    output: String,
+    // keep packets within [time_start, time_end) timestamps
+    #[arg(long)]
+    time_start: Option<u64>,
+    #[arg(long)]
+    time_end: Option<u64>,
+    // keep packets within [insn_start, insn_end) executed instructions
+    #[arg(long)]
+    insn_start: Option<u64>,
+    #[arg(long)]
+    insn_end: Option<u64>,
+}
+
+// where the frontend sends decoded entries
+trait EntrySink {
+    fn broadcast(&mut self, entry: Entry);
+}
+
+impl EntrySink for RoiGate {
+    fn broadcast(&mut self, entry: Entry) {
+        RoiGate::broadcast(self, entry);
+    }
+}
+
+// only counts executed instructions and follows the context, for slicing
+struct InsnCounter {
+    insn_count: u64,
+    context: ContextTracker,
+}
+
+impl EntrySink for InsnCounter {
+    fn broadcast(&mut self, entry: Entry) {
+        let mut entry = entry;
+        self.context.stamp(&mut entry);
+        if entry.event == Event::None {
+            self.insn_count += 1;
+        }
+    }
 }
 
 fn refund_addr(addr: u64) -> u64 {
@@ -119,7 +316,7 @@ fn compute_offset(insn: &Insn) -> i64 {
 }
 
 // step until encountering a br/jump
-fn step_bb(pc: u64, insn_map: &HashMap<u64, &Insn>, bus: &mut Bus<Entry>) -> u64 {
+fn step_bb(pc: u64, insn_map: &HashMap<u64, &Insn>, bus: &mut impl EntrySink) -> u64 {
     let mut pc = pc;
     loop {
         let insn = insn_map.get(&pc).unwrap();
@@ -136,7 +333,7 @@ fn step_bb(pc: u64, insn_map: &HashMap<u64, &Insn>, bus: &mut Bus<Entry>) -> u64
     pc
 }
 
-fn step_bb_until(pc: u64, insn_map: &HashMap<u64, &Insn>, target_pc: u64, bus: &mut Bus<Entry>) -> u64 {
+fn step_bb_until(pc: u64, insn_map: &HashMap<u64, &Insn>, target_pc: u64, bus: &mut impl EntrySink) -> u64 {
     println!("stepping bb from pc: {:x} until pc: {:x}", pc, target_pc);
     let mut pc = pc;
     loop {
@@ -153,9 +350,8 @@ fn step_bb_until(pc: u64, insn_map: &HashMap<u64, &Insn>, target_pc: u64, bus: &
     pc
 }
 
-// frontend decoding packets and pushing entries to the bus
-fn trace_decoder(args: &Args, mut bus: Bus<Entry>) -> Result<()> {
-    let mut elf_file = File::open(args.binary.clone())?;
+fn disassemble<'a>(cs: &'a Capstone, binary: &str) -> Result<Instructions<'a>> {
+    let mut elf_file = File::open(binary)?;
     let mut elf_buffer = Vec::new();
     elf_file.read_to_end(&mut elf_buffer)?;
     let elf = object::File::parse(&*elf_buffer)?;
@@ -166,109 +362,267 @@ fn trace_decoder(args: &Args, mut bus: Bus<Entry>) -> Result<()> {
     let text_data = text_section.data()?;
     let entry_point = elf.entry();
 
-    let cs = Capstone::new()
+    let decoded_instructions = cs.disasm_all(text_data, entry_point)?;
+    debug!("[main] found {} instructions", decoded_instructions.len());
+    Ok(decoded_instructions)
+}
+
+fn riscv_capstone() -> Result<Capstone> {
+    Ok(Capstone::new()
         .riscv()
         .mode(ArchMode::RiscV64)
         .extra_mode([ArchExtraMode::RiscVC].iter().copied())
         .detail(true)
-        .build()?;
+        .build()?)
+}
 
-    let decoded_instructions = cs.disasm_all(&text_data, entry_point)?;
-    debug!("[main] found {} instructions", decoded_instructions.len());
+// function entries, to tell tail calls from plain jumps
+fn func_entries(symbol_elf: &object::File) -> HashSet<u64> {
+    symbol_elf.symbols()
+        .filter(|s| s.kind() == object::SymbolKind::Text)
+        .map(|s| s.address())
+        .collect()
+}
 
-    // create a map of address to instruction 
-    let mut insn_map : HashMap<u64, &Insn> = HashMap::new();
-    for insn in decoded_instructions.as_ref() {
-        insn_map.insert(insn.address(), insn);
+// decode one packet (anything but the final FSync) from pc, returning the new pc
+fn decode_packet(packet: &Packet, pc: u64, timestamp: &mut u64, insn_map: &HashMap<u64, &Insn>, func_entries: &HashSet<u64>, bus: &mut impl EntrySink) -> u64 {
+    let mut pc = pc;
+    if packet.f_header == FHeader::FTrap {
+        pc = step_bb_until(pc, insn_map, packet.trap_address, bus);
+        let new_pc = refund_addr(packet.target_address ^ (pc >> 1));
+        *timestamp += packet.timestamp;
+        // from the trapping pc to the handler (or back from it on a trap return)
+        bus.broadcast(Entry::new_timed_trap(packet.trap_type, *timestamp, pc, new_pc));
+        pc = new_pc;
+    } else if packet.f_header == FHeader::FVal {
+        // reported values do not move the pc
+        *timestamp += packet.timestamp;
+        if packet.val_type != ValType::VNone {
+            bus.broadcast(Entry::new_timed_value(packet.val_type, *timestamp, pc, packet.value));
+        }
+    } else {
+        pc = step_bb(pc, insn_map, bus);
+        let insn_to_resolve = insn_map.get(&pc).unwrap();
+        trace!("pc: {:x}", pc);
+        *timestamp += packet.timestamp;
+        let timestamp = *timestamp;
+        match packet.f_header {
+            FHeader::FTb => {
+                assert!(BRANCH_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
+                let new_pc = (pc as i64 + compute_offset(insn_to_resolve) as i64) as u64;
+                bus.broadcast(Entry::new_timed_event(Event::TakenBranch, timestamp, pc, new_pc));
+                pc = new_pc;
+            }
+            FHeader::FNt => {
+                assert!(BRANCH_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
+                let new_pc = pc + insn_to_resolve.len() as u64;
+                bus.broadcast(Entry::new_timed_event(Event::NonTakenBranch, timestamp, pc, new_pc));
+                pc = new_pc;
+            }
+            FHeader::FIj => {
+                assert!(JUMP_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
+                let new_pc = (pc as i64 + compute_offset(insn_to_resolve) as i64) as u64;
+                let cf_kind = ControlFlowKind::from_jump(insn_to_resolve.mnemonic().unwrap(), insn_to_resolve.op_str().unwrap(), func_entries.contains(&new_pc));
+                bus.broadcast(Entry::new_timed_jump(Event::InferrableJump, cf_kind, timestamp, pc, new_pc));
+                pc = new_pc;
+            }
+            FHeader::FUj => {
+                assert!(JUMP_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
+                let new_pc = refund_addr(packet.target_address ^ (pc >> 1));
+                let cf_kind = ControlFlowKind::from_jump(insn_to_resolve.mnemonic().unwrap(), insn_to_resolve.op_str().unwrap(), func_entries.contains(&new_pc));
+                bus.broadcast(Entry::new_timed_jump(Event::UninferableJump, cf_kind, timestamp, pc, new_pc));
+                pc = new_pc;
+            }
+            _ => {
+                panic!("unknown FHeader: {:?}", packet.f_header);
+            }
+        }
     }
+    pc
+}
 
-    let encoded_trace_file = File::open(args.encoded_trace.clone())?;
-    let mut encoded_trace_reader : BufReader<File> = BufReader::new(encoded_trace_file);
-
-    let packet = frontend::packet::read_packet(&mut encoded_trace_reader)?;
+// decode a trace from its start packet to the FSync ending it, returning the last pc
+fn decode_trace(reader: &mut impl Read, insn_map: &HashMap<u64, &Insn>, func_entries: &HashSet<u64>, bus: &mut impl EntrySink) -> Result<u64> {
+    let packet = frontend::packet::read_packet(reader)?;
     trace!("packet: {:?}", packet);
     let mut pc = refund_addr(packet.target_address);
     let mut timestamp = packet.timestamp;
     bus.broadcast(Entry::new_timed_event(Event::Start, packet.timestamp, pc, 0));
 
-    while let Ok(packet) = frontend::packet::read_packet(&mut encoded_trace_reader) {
+    while let Ok(packet) = frontend::packet::read_packet(reader) {
         // special handling for the last packet, should be unlikely hinted
         trace!("packet: {:?}", packet);
         if packet.f_header == FHeader::FSync {
-            pc = step_bb_until(pc, &insn_map, refund_addr(packet.target_address), &mut bus);
+            pc = step_bb_until(pc, insn_map, refund_addr(packet.target_address), bus);
             println!("detected FSync packet, trace ending!");
             bus.broadcast(Entry::new_timed_event(Event::End, packet.timestamp, pc, 0));
             break;
-        } else if packet.f_header == FHeader::FTrap {
-            bus.broadcast(Entry::new_timed_trap(packet.trap_type, packet.timestamp, pc, packet.trap_address));
-            pc = step_bb_until(pc, &insn_map, packet.trap_address, &mut bus);
-            pc = refund_addr(packet.target_address ^ (pc >> 1));
-            timestamp += packet.timestamp;
         } else {
-            pc = step_bb(pc, &insn_map, &mut bus);
-            let insn_to_resolve = insn_map.get(&pc).unwrap();
-            trace!("pc: {:x}", pc);
-            timestamp += packet.timestamp;
-            match packet.f_header {
-                FHeader::FTb => {
-                    assert!(BRANCH_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
-                    let new_pc = (pc as i64 + compute_offset(insn_to_resolve) as i64) as u64;
-                    bus.broadcast(Entry::new_timed_event(Event::TakenBranch, timestamp, pc, new_pc));
-                    pc = new_pc;
-                }
-                FHeader::FNt => {
-                    assert!(BRANCH_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
-                    let new_pc = pc + insn_to_resolve.len() as u64;
-                    bus.broadcast(Entry::new_timed_event(Event::NonTakenBranch, timestamp, pc, new_pc));
-                    pc = new_pc;
-                }
-                FHeader::FIj => {
-                    assert!(JUMP_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
-                    let new_pc = (pc as i64 + compute_offset(insn_to_resolve) as i64) as u64;
-                    bus.broadcast(Entry::new_timed_event(Event::InferrableJump, timestamp, pc, new_pc));
-                    pc = new_pc;
-                }
-                FHeader::FUj => {
-                    assert!(JUMP_OPCODES.contains(&insn_to_resolve.mnemonic().unwrap()));
-                    let new_pc = refund_addr(packet.target_address ^ (pc >> 1));
-                    bus.broadcast(Entry::new_timed_event(Event::UninferableJump, timestamp, pc, new_pc));
-                    pc = new_pc;
-                }
-                FHeader::FTrap => {
-                    bus.broadcast(Entry::new_timed_trap(packet.trap_type, packet.timestamp, pc, packet.trap_address));
-                    pc = refund_addr(packet.target_address ^ (pc >> 1));
-                }
-                _ => {
-                    panic!("unknown FHeader: {:?}", packet.f_header);
-                }
-            }
-            // log the timestamp
+            pc = decode_packet(&packet, pc, &mut timestamp, insn_map, func_entries, bus);
         }
+        // e.g. a hijacked jump into the middle of an instruction, there is no way to follow it
+        if !insn_map.contains_key(&pc) {
+            println!("pc {:x} is not a decoded instruction, trace ending!", pc);
+            bus.broadcast(Entry::new_timed_event(Event::End, timestamp, pc, 0));
+            break;
+        }
+    }
+    Ok(pc)
+}
+
+// frontend decoding packets and pushing entries to the bus
+fn trace_decoder(args: &Args, debug_path: &str, bus: Bus<Entry>) -> Result<()> {
+    let cs = riscv_capstone()?;
+    let decoded_instructions = disassemble(&cs, &args.binary)?;
+
+    // create a map of address to instruction 
+    let mut insn_map : HashMap<u64, &Insn> = HashMap::new();
+    for insn in decoded_instructions.as_ref() {
+        insn_map.insert(insn.address(), insn);
     }
 
+    let symbol_data = std::fs::read(debug_info::symbol_file(&args.binary, debug_path)?)?;
+    let symbol_elf = object::File::parse(&*symbol_data)?;
+    let func_entries = func_entries(&symbol_elf);
+
+    // resolve region of interest markers, by raw or demangled name
+    let resolve_marker = |marker: &String| -> Result<u64> {
+        if let Some(hex) = marker.strip_prefix("0x") {
+            return Ok(u64::from_str_radix(hex, 16)?);
+        }
+        symbol_elf.symbols()
+            .filter(|s| s.kind() == object::SymbolKind::Text)
+            .find(|s| s.name().is_ok_and(|name| name == marker || demangle_name(name) == *marker))
+            .map(|s| s.address())
+            .ok_or_else(|| anyhow::anyhow!("unknown region of interest marker: {}", marker))
+    };
+    let roi = Roi {
+        start_addrs: args.roi_start.iter().map(resolve_marker).collect::<Result<_>>()?,
+        stop_addrs: args.roi_stop.iter().map(resolve_marker).collect::<Result<_>>()?,
+        time_window: (args.roi_time_start, args.roi_time_end),
+        insn_window: (args.roi_insn_start, args.roi_insn_end),
+    };
+    let mut bus = RoiGate::new(bus, roi, ContextTracker::new(args.start_mode, args.trap_mode));
+
+    let encoded_trace_file = File::open(args.encoded_trace.clone())?;
+    let mut encoded_trace_reader : BufReader<File> = BufReader::new(encoded_trace_file);
+    let pc = decode_trace(&mut encoded_trace_reader, &insn_map, &func_entries, &mut bus)?;
+
+    bus.finish(pc);
     drop(bus);
     println!("[fe-decoder] bus dropped");
 
     Ok(())
 }
 
+// copy the packets within a window into a new trace, led by a start packet with
+// the absolute pc and timestamp at the window and closed by an FSync
+fn slice_trace(args: &Args, slice_args: &SliceArgs, debug_path: &str) -> Result<()> {
+    let cs = riscv_capstone()?;
+    let decoded_instructions = disassemble(&cs, &args.binary)?;
+    let mut insn_map : HashMap<u64, &Insn> = HashMap::new();
+    for insn in decoded_instructions.as_ref() {
+        insn_map.insert(insn.address(), insn);
+    }
+    let symbol_data = std::fs::read(debug_info::symbol_file(&args.binary, debug_path)?)?;
+    let func_entries = func_entries(&object::File::parse(&*symbol_data)?);
+
+    let encoded_trace_file = File::open(args.encoded_trace.clone())?;
+    let mut encoded_trace_reader : BufReader<File> = BufReader::new(encoded_trace_file);
+    let mut writer = BufWriter::new(File::create(&slice_args.output)?);
+    let context = ContextTracker::new(args.start_mode, args.trap_mode);
+    let packets = slice_packets(&mut encoded_trace_reader, &mut writer, slice_args, &insn_map, &func_entries, context)?;
+    writer.flush()?;
+    println!("[slice] {} packets written to {}", packets, slice_args.output);
+    Ok(())
+}
+
+// the packets of slice_trace, returning how many were written
+fn slice_packets(reader: &mut impl Read, writer: &mut impl Write, slice_args: &SliceArgs, insn_map: &HashMap<u64, &Insn>, func_entries: &HashSet<u64>, context: ContextTracker) -> Result<usize> {
+    let packet = frontend::packet::read_packet(reader)?;
+    let mut pc = refund_addr(packet.target_address);
+    let mut timestamp = packet.timestamp;
+    let mut counter = InsnCounter { insn_count: 0, context };
+    let mut started = false;
+    let mut packets = 0;
+
+    while let Ok(packet) = frontend::packet::read_packet(reader) {
+        // the window is checked against the state before each packet
+        let reached_start = slice_args.time_start.is_none_or(|start| timestamp >= start)
+            && slice_args.insn_start.is_none_or(|start| counter.insn_count >= start);
+        let reached_end = slice_args.time_end.is_some_and(|end| timestamp >= end)
+            || slice_args.insn_end.is_some_and(|end| counter.insn_count >= end);
+        if reached_end {
+            break;
+        }
+        if reached_start && !started {
+            println!("slice starts at pc: {:x}, timestamp: {}, instruction: {}", pc, timestamp, counter.insn_count);
+            frontend::packet::write_packet(writer, &frontend::packet::sync_packet(pc, timestamp))?;
+            started = true;
+            packets += 1;
+            // the context so far is not in the slice, report it right after the start
+            let context = counter.context.context();
+            frontend::packet::write_packet(writer, &frontend::packet::value_packet(ValType::VMode, context.mode.level()))?;
+            packets += 1;
+            if let Some(asid) = context.asid {
+                frontend::packet::write_packet(writer, &frontend::packet::value_packet(ValType::VAsid, asid))?;
+                packets += 1;
+            }
+        }
+        if packet.f_header == FHeader::FSync {
+            // the trace ends inside the window, keep its own FSync
+            if started {
+                frontend::packet::write_packet(writer, &packet)?;
+                return Ok(packets + 1);
+            }
+            break;
+        }
+        if started {
+            frontend::packet::write_packet(writer, &packet)?;
+            packets += 1;
+        }
+        pc = decode_packet(&packet, pc, &mut timestamp, insn_map, func_entries, &mut counter);
+        if !insn_map.contains_key(&pc) {
+            break;
+        }
+    }
+
+    if !started {
+        return Err(anyhow::anyhow!("the slice window is past the end of the trace"));
+    }
+    // the decoder always steps the instruction at pc on the FSync, stop right after it
+    println!("slice ends at pc: {:x}, timestamp: {}, instruction: {}", pc, timestamp, counter.insn_count);
+    let end_pc = insn_map.get(&pc).map_or(pc, |insn| pc + insn.len() as u64);
+    frontend::packet::write_packet(writer, &frontend::packet::sync_packet(end_pc, timestamp))?;
+    Ok(packets + 1)
+}
+
 fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
 
     let mut bus: Bus<Entry> = Bus::new(BUS_SIZE);
     let mut receivers: Vec<Box<dyn AbstractReceiver>> = vec![];
+
+    // the file carrying DWARF, the binary itself unless it is stripped
+    let debug_path = debug_info::find_debug_file(&args.binary, &args.debug_dir)?;
+    let mut filters = filter::parse_filters(&args.filter)?;
+
+    if let Some(Command::Slice(slice_args)) = args.command.as_ref() {
+        return slice_trace(&args, slice_args, &debug_path);
+    }
     
     // add a receiver to the bus for txt output
     if args.to_txt {
         let txt_bus_endpoint = bus.add_rx();
-        receivers.push(Box::new(TxtReceiver::new(txt_bus_endpoint)));
+        let inline_elf_paths = if args.txt_inline { Some((args.binary.clone(), debug_path.clone())) } else { None };
+        receivers.push(filter::with_filter("txt", Box::new(TxtReceiver::new(txt_bus_endpoint, inline_elf_paths)), &mut filters, &args.binary, &debug_path));
     }
 
     // add a receiver to the bus for json output
     if args.to_json {
         let json_bus_endpoint = bus.add_rx();
-        receivers.push(Box::new(JsonReceiver::new(json_bus_endpoint)));
+        receivers.push(filter::with_filter("json", Box::new(JsonReceiver::new(json_bus_endpoint)), &mut filters, &args.binary, &debug_path));
     }
 
     if args.to_afdo {
@@ -277,26 +631,96 @@ fn main() -> Result<()> {
         let mut elf_buffer = Vec::new();
         elf_file.read_to_end(&mut elf_buffer)?;
         let elf = object::File::parse(&*elf_buffer)?;
-        receivers.push(Box::new(AfdoReceiver::new(afdo_bus_endpoint, elf.entry().clone())));
+        receivers.push(filter::with_filter("afdo", Box::new(AfdoReceiver::new(afdo_bus_endpoint, elf.entry().clone(), args.split_by)), &mut filters, &args.binary, &debug_path));
         drop(elf_file);
     }
 
     if args.to_gcda {
         let gcda_bus_endpoint = bus.add_rx();
-        receivers.push(Box::new(GcdaReceiver::new(gcda_bus_endpoint, args.gcno.clone(), args.binary.clone())));
+        receivers.push(filter::with_filter("gcda", Box::new(GcdaReceiver::new(gcda_bus_endpoint, args.gcno.clone(), args.binary.clone(), debug_path.clone())), &mut filters, &args.binary, &debug_path));
     }
 
     if args.to_speedscope {
         let speedscope_bus_endpoint = bus.add_rx();
-        receivers.push(Box::new(SpeedscopeReceiver::new(speedscope_bus_endpoint, args.binary.clone())));
+        receivers.push(filter::with_filter("speedscope", Box::new(SpeedscopeReceiver::new(speedscope_bus_endpoint, args.binary.clone(), debug_path.clone(), args.split_by, args.switch_symbol.clone())), &mut filters, &args.binary, &debug_path));
     }
 
     if args.to_vpp {
         let vpp_bus_endpoint = bus.add_rx();
-        receivers.push(Box::new(VPPReceiver::new(vpp_bus_endpoint, args.binary.clone())));
+        receivers.push(filter::with_filter("vpp", Box::new(VPPReceiver::new(vpp_bus_endpoint, args.binary.clone(), debug_path.clone())), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_cfi {
+        let cfi_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("cfi", Box::new(CfiReceiver::new(cfi_bus_endpoint, args.binary.clone(), debug_path.clone(), args.switch_symbol.clone())), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_indirect {
+        let indirect_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("indirect", Box::new(IndirectReceiver::new(indirect_bus_endpoint, args.binary.clone(), debug_path.clone())), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_flight_recorder {
+        let flight_recorder_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("flight_recorder", Box::new(FlightRecorderReceiver::new(flight_recorder_bus_endpoint, args.binary.clone(), debug_path.clone(), args.flight_recorder_blocks, args.flight_recorder_trigger.clone())?), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_backtrace {
+        let backtrace_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("backtrace", Box::new(BacktraceReceiver::new(backtrace_bus_endpoint, args.binary.clone(), debug_path.clone(), args.switch_symbol.clone())), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_chrome_trace {
+        let chrome_trace_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("chrome_trace", Box::new(ChromeTraceReceiver::new(chrome_trace_bus_endpoint, args.binary.clone(), debug_path.clone(), args.clock_hz)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_folded {
+        let folded_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("folded", Box::new(FoldedReceiver::new(folded_bus_endpoint, args.binary.clone(), debug_path.clone(), args.folded_weight, args.folded_lines)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_callgrind {
+        let callgrind_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("callgrind", Box::new(CallgrindReceiver::new(callgrind_bus_endpoint, args.binary.clone(), debug_path.clone())), &mut filters, &args.binary, &debug_path));
     }
 
-    let frontend_handle = thread::spawn(move || trace_decoder(&args, bus));
+    if args.to_pprof {
+        let pprof_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("pprof", Box::new(PprofReceiver::new(pprof_bus_endpoint, args.binary.clone(), debug_path.clone(), args.clock_hz)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_firefox {
+        let firefox_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("firefox", Box::new(FirefoxReceiver::new(firefox_bus_endpoint, args.binary.clone(), debug_path.clone(), args.clock_hz, args.sample_interval)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_ctf {
+        let ctf_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("ctf", Box::new(CtfReceiver::new(ctf_bus_endpoint, args.binary.clone(), debug_path.clone(), args.clock_hz)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_vcd {
+        backend::vcd_receiver::check_timescale(&args.vcd_timescale)?;
+        let vcd_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("vcd", Box::new(VcdReceiver::new(vcd_bus_endpoint, args.binary.clone(), debug_path.clone(), args.vcd_timescale.clone())), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_function_graph {
+        let function_graph_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("function_graph", Box::new(FunctionGraphReceiver::new(function_graph_bus_endpoint, args.binary.clone(), debug_path.clone(), args.graph_hart, args.graph_max_depth, args.graph_min_cycles)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if args.to_uftrace {
+        let uftrace_bus_endpoint = bus.add_rx();
+        receivers.push(filter::with_filter("uftrace", Box::new(UftraceReceiver::new(uftrace_bus_endpoint, args.binary.clone(), debug_path.clone(), args.clock_hz)), &mut filters, &args.binary, &debug_path));
+    }
+
+    if let Some(name) = filters.keys().next() {
+        return Err(anyhow::anyhow!("filter for a receiver that is not enabled: {}", name));
+    }
+
+    let frontend_handle = thread::spawn(move || trace_decoder(&args, &debug_path, bus));
     let receiver_handles: Vec<_> = receivers.into_iter()
         .map(|mut receiver| thread::spawn(move || receiver.try_receive_loop()))
         .collect();
@@ -309,3 +733,110 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use frontend::packet::{sync_packet, write_packet, CHeader};
+
+    // li a0, 3; 1: jal f; bnez a0, 1b; nop; f: addi a0, a0, -1; ret
+    const CODE: [u32; 6] = [0x00300513, 0x00c000ef, 0xfe051ee3, 0x00000013, 0xfff50513, 0x00008067];
+    const BASE: u64 = 0x1000;
+
+    impl EntrySink for Vec<Entry> {
+        fn broadcast(&mut self, entry: Entry) {
+            self.push(entry);
+        }
+    }
+
+    fn compressed(c_header: CHeader, timestamp: u64) -> Packet {
+        let mut packet = Packet::new();
+        packet.is_compressed = true;
+        packet.f_header = FHeader::from(c_header.clone());
+        packet.c_header = c_header;
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    fn uninferable_jump(from: u64, to: u64, timestamp: u64) -> Packet {
+        let mut packet = Packet::new();
+        packet.f_header = FHeader::FUj;
+        packet.target_address = (to >> 1) ^ (from >> 1);
+        packet.timestamp = timestamp;
+        packet
+    }
+
+    // the three rounds of the loop, each taking a tick per packet
+    fn encoded_trace() -> Vec<u8> {
+        let mut packets = vec![sync_packet(BASE, 0)];
+        for round in 0..3 {
+            packets.push(compressed(CHeader::CIj, 1));
+            packets.push(uninferable_jump(BASE + 0x14, BASE + 0x8, 1));
+            packets.push(compressed(if round < 2 { CHeader::CTb } else { CHeader::CNt }, 1));
+        }
+        packets.push(sync_packet(BASE + 0x10, 10));
+        let mut encoded = Vec::new();
+        for packet in packets.iter() {
+            write_packet(&mut encoded, packet).unwrap();
+        }
+        encoded
+    }
+
+    fn decode(encoded: &[u8], insn_map: &HashMap<u64, &Insn>) -> Vec<Entry> {
+        let mut entries = Vec::new();
+        decode_trace(&mut &encoded[..], insn_map, &HashSet::from([BASE, BASE + 0x10]), &mut entries).unwrap();
+        entries
+    }
+
+    fn key(entry: &Entry) -> (Event, ControlFlowKind, (u64, u64), Option<u64>) {
+        (entry.event, entry.cf_kind, entry.arc, entry.timestamp)
+    }
+
+    fn slice_args(time: (Option<u64>, Option<u64>), insn: (Option<u64>, Option<u64>)) -> SliceArgs {
+        SliceArgs { output: String::new(), time_start: time.0, time_end: time.1, insn_start: insn.0, insn_end: insn.1 }
+    }
+
+    #[test]
+    fn test_slice_decodes_like_its_window() {
+        let cs = riscv_capstone().unwrap();
+        let code: Vec<u8> = CODE.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let insns = cs.disasm_all(&code, BASE).unwrap();
+        let insn_map: HashMap<u64, &Insn> = insns.iter().map(|insn| (insn.address(), insn)).collect();
+        let encoded = encoded_trace();
+        let full = decode(&encoded, &insn_map);
+        assert_eq!(full.first().unwrap().event, Event::Start);
+        assert_eq!(full.last().unwrap().event, Event::End);
+        let full: Vec<_> = full.iter().filter(|entry| !matches!(entry.event, Event::Start | Event::End)).map(key).collect();
+
+        let windows = [
+            slice_args((None, None), (Some(4), Some(12))),
+            slice_args((None, None), (Some(6), None)),
+            slice_args((Some(2), Some(5)), (None, None)),
+            slice_args((Some(8), None), (None, None)),
+        ];
+        for window in windows.iter() {
+            let mut sliced = Vec::new();
+            slice_packets(&mut &encoded[..], &mut sliced, window, &insn_map, &HashSet::from([BASE, BASE + 0x10]), ContextTracker::new(PrivMode::M, PrivMode::M)).unwrap();
+            let slice = decode(&sliced, &insn_map);
+            // the slice starts where its first instruction is and reports the mode it starts in
+            assert_eq!(slice[0].event, Event::Start);
+            assert_eq!(slice[0].arc.0, slice[2].arc.0);
+            assert_eq!(slice[1].event, Event::ModeChange);
+            assert_eq!(slice.last().unwrap().event, Event::End);
+            let slice: Vec<_> = slice[2..slice.len() - 1].iter().map(key).collect();
+            assert!(!slice.is_empty() && slice.len() < full.len());
+            assert!(full.windows(slice.len()).any(|window| window == slice), "slice {:?} is not a window of {:?}", slice, full);
+        }
+    }
+
+    #[test]
+    fn test_slice_past_the_end() {
+        let cs = riscv_capstone().unwrap();
+        let code: Vec<u8> = CODE.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let insns = cs.disasm_all(&code, BASE).unwrap();
+        let insn_map: HashMap<u64, &Insn> = insns.iter().map(|insn| (insn.address(), insn)).collect();
+        let mut sliced = Vec::new();
+        let window = slice_args((Some(100), None), (None, None));
+        assert!(slice_packets(&mut &encoded_trace()[..], &mut sliced, &window, &insn_map, &HashSet::new(), ContextTracker::new(PrivMode::M, PrivMode::M)).is_err());
+    }
+}